@@ -0,0 +1,186 @@
+use crate::brood_flow_config::Configuration;
+use crate::broodminder_device::{BroodminderDevice, WEATHER_MODEL};
+use crate::publisher::Publisher;
+use chrono::prelude::Utc;
+use json::{object, JsonValue};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+// How often the summary is recomputed and published
+const SUMMARY_INTERVAL: Duration = Duration::from_secs(60);
+// Devices heard within this long count as reporting
+const REPORTING_WINDOW_MS: i64 = 3600000;
+// Config is resent this often, like the devices' own config
+const CONFIG_INTERVAL_MS: i64 = 3600000;
+
+// Apiary wide figures across every hive sensor
+#[derive(Debug, PartialEq)]
+pub struct ApiarySummary {
+  pub average_temperature_c: Option<f64>, // None while no hive sensor is reporting
+  pub reporting: usize,                   // Hive sensors heard within REPORTING_WINDOW_MS
+}
+
+impl ApiarySummary {
+  // The summary of the devices heard recently. The weather station measures outside the hives, so
+  // it isn't counted
+  pub fn of(
+    devices: &HashMap<String, BroodminderDevice>,
+    now: i64,
+    decimal_places: u32,
+  ) -> ApiarySummary {
+    let temperatures: Vec<f64> = devices
+      .values()
+      .filter(|device| device.model_info().is_some() && device.model != WEATHER_MODEL)
+      .filter(|device| now - device.last_seen() <= REPORTING_WINDOW_MS)
+      .map(|device| device.realtime_temperature_c as f64)
+      .collect();
+
+    let average_temperature_c = if temperatures.is_empty() {
+      None
+    } else {
+      let mean = temperatures.iter().sum::<f64>() / temperatures.len() as f64;
+      let factor = 10f64.powi(decimal_places as i32);
+      Some((mean * factor).round() / factor)
+    };
+    ApiarySummary {
+      average_temperature_c,
+      reporting: temperatures.len(),
+    }
+  }
+
+  fn to_json(&self) -> JsonValue {
+    let mut state = object! { reporting: self.reporting };
+    if let Some(temperature) = self.average_temperature_c {
+      state["average_temperature_c"] = temperature.into();
+    }
+    state
+  }
+}
+
+fn state_topic(settings: &Configuration) -> String {
+  format!(
+    "{}/sensor/brood-flow-summary/state",
+    settings.discovery_prefix
+  )
+}
+
+// One config message per summary sensor, grouped under an "Apiary" device in HA
+fn config_messages(settings: &Configuration) -> Vec<(String, JsonValue)> {
+  let sensors = [
+    ("average_temperature", "average_temperature_c", Some("°C")),
+    ("reporting", "reporting", None),
+  ];
+  sensors
+    .iter()
+    .map(|(id, state_key, unit)| {
+      let mut config_message = object! {
+        name: format!("Apiary {}", id.replace('_', " ")),
+        state_topic: state_topic(settings),
+        state_class: "measurement",
+        unique_id: format!("{}_apiary_{}", settings.client_id, id),
+        value_template: format!("{{{{ value_json.{} }}}}", state_key),
+        availability_topic: settings.availability_topic.clone(),
+        device: {
+          identifiers: [format!("{}_apiary", settings.client_id)],
+          name: "Apiary",
+          manufacturer: "brood-flow",
+          model: "Apiary summary",
+        },
+      };
+      match unit {
+        Some(unit) => {
+          config_message["device_class"] = "temperature".into();
+          config_message["unit_of_measurement"] = (*unit).into();
+        }
+        None => config_message["icon"] = "mdi:beehive-outline".into(),
+      }
+      let topic = format!(
+        "{}/sensor/brood-flow-summary/{}/config",
+        settings.discovery_prefix, id
+      );
+      (topic, config_message)
+    })
+    .collect()
+}
+
+// Publishes the apiary summary every SUMMARY_INTERVAL, from the decoder's map of devices
+pub fn start(
+  devices: Arc<Mutex<HashMap<String, BroodminderDevice>>>,
+  publisher: Publisher,
+  settings: Arc<Configuration>,
+) {
+  tokio::task::spawn(async move {
+    let mut interval = tokio::time::interval(SUMMARY_INTERVAL);
+    let mut last_config_sent = 0;
+    let qos = settings.qos.qos();
+    loop {
+      interval.tick().await;
+      let now = Utc::now().timestamp_millis();
+
+      if settings.publish_discovery && now - last_config_sent > CONFIG_INTERVAL_MS {
+        for (topic, config_message) in config_messages(&settings) {
+          publisher.publish(
+            topic,
+            qos,
+            settings.retain,
+            config_message.dump(),
+            "summary config",
+          );
+        }
+        last_config_sent = now;
+      }
+
+      let summary = ApiarySummary::of(&devices.lock().unwrap(), now, settings.decimal_places);
+      publisher.publish(
+        state_topic(&settings),
+        qos,
+        settings.retain,
+        summary.to_json().dump(),
+        "summary",
+      );
+    }
+  });
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn device(model: u8, temperature_c: f32, last_seen: i64) -> BroodminderDevice {
+    let mut data = vec![0u8; 25];
+    data[0] = model;
+    let mut device = BroodminderDevice::build_broodminder_device(&data).unwrap();
+    device.realtime_temperature_c = temperature_c;
+    device.record_source("hci0".to_string(), None, last_seen);
+    device
+  }
+
+  #[test]
+  fn summary_averages_reporting_hive_sensors() {
+    let now = 1_700_000_000_000;
+    let mut devices = HashMap::new();
+    devices.insert("a".to_string(), device(47, 34.0, now));
+    devices.insert("b".to_string(), device(57, 30.5, now - 60000));
+    // Too long ago, outside the hives, and a model brood-flow doesn't know
+    devices.insert("c".to_string(), device(47, 10.0, now - 2 * 3600000));
+    devices.insert("d".to_string(), device(WEATHER_MODEL, -5.0, now));
+    devices.insert("e".to_string(), device(99, 20.0, now));
+
+    let summary = ApiarySummary::of(&devices, now, 2);
+    assert_eq!(
+      summary,
+      ApiarySummary {
+        average_temperature_c: Some(32.25),
+        reporting: 2,
+      }
+    );
+    assert_eq!(
+      summary.to_json().dump(),
+      r#"{"reporting":2,"average_temperature_c":32.25}"#
+    );
+
+    let summary = ApiarySummary::of(&HashMap::new(), now, 2);
+    assert_eq!(summary.to_json().dump(), r#"{"reporting":0}"#);
+  }
+}
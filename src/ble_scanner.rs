@@ -0,0 +1,216 @@
+use crate::broodminder_device::{BroodminderDevice, MANUFACTURER_ID};
+use crate::error::Error;
+use btleplug::api::{Central, CentralEvent, Manager as _, Peripheral as _, ScanFilter};
+use btleplug::platform::{Adapter, Manager};
+use futures::stream::{Stream, StreamExt};
+use std::collections::{BTreeMap, HashSet};
+use std::pin::Pin;
+use std::time::Duration;
+use tokio::sync::mpsc::Sender;
+
+// A Broodminder advertisement heard by one of the adapters, forwarded to the device loop
+#[derive(Debug)]
+pub struct Advertisement {
+  pub adapter: String, // The adapter_info() of the adapter that heard this advertisement
+  pub address: String, // The MAC address, which (unlike the local name) is unique per sensor
+  pub local_name: String, // The address until the device's name has been heard
+  pub rssi: Option<i16>,
+  pub data: Vec<u8>, // The manufacturer data for id 653
+  // The byte length of the data under every manufacturer id the advertisement has, 653 included,
+  // for spotting devices that advertise more than the Broodminder payload
+  pub manufacturer_lengths: BTreeMap<u16, usize>,
+}
+
+// Returns every bluetooth adapter on the system, or only those whose adapter info contains one of
+// the configured names (e.g. "hci0") if a filter is given
+pub async fn get_centrals(
+  manager: &Manager,
+  filter: &Option<Vec<String>>,
+) -> Result<Vec<Adapter>, Error> {
+  let adapters = manager.adapters().await?;
+  let mut centrals = Vec::new();
+
+  for adapter in adapters {
+    let info = adapter.adapter_info().await.unwrap_or_default();
+    match filter {
+      Some(names) if !names.iter().any(|name| info.contains(name.as_str())) => {
+        info!("Skipping adapter {} (not in configured adapters)", info);
+      }
+      _ => {
+        info!("Using adapter {}", info);
+        centrals.push(adapter);
+      }
+    }
+  }
+
+  Ok(centrals)
+}
+
+// How long to wait before restarting a scan whose event stream ended, doubling after each failed
+// restart up to the max
+const RESTART_DELAY: Duration = Duration::from_secs(5);
+const MAX_RESTART_DELAY: Duration = Duration::from_secs(300);
+
+// Subscribes to the adapter's events and starts scanning
+async fn start_scan(
+  central: &Adapter,
+) -> Result<Pin<Box<dyn Stream<Item = CentralEvent> + Send>>, btleplug::Error> {
+  // Each adapter has an event stream, we fetch via events(),
+  // This will return what is essentially:
+  // Future<Result<Stream<Item=CentralEvent>>>.
+  let events = central.events().await?;
+
+  // Start scanning for BTLE devices
+  // TODO: Add a scan filter?
+  central.start_scan(ScanFilter::default()).await?;
+  Ok(events)
+}
+
+// Starts scanning on the adapter and spawns a task that forwards each Broodminder advertisement it
+// hears to the device loop. If accepted_models is set, advertisements from other models are dropped.
+// The event stream ends if the adapter goes away (e.g. a USB dongle is unplugged), the scan is then
+// restarted with a backoff until the adapter is back
+pub async fn start_scanner(
+  central: Adapter,
+  advertisements: Sender<Advertisement>,
+  accepted_models: Option<Vec<u8>>,
+) -> Result<(), Error> {
+  let adapter_name = central.adapter_info().await?;
+  let mut events = start_scan(&central).await?;
+
+  tokio::task::spawn(async move {
+    // Devices we've explained the missing name of, so it's only logged once each
+    let mut unnamed: HashSet<String> = HashSet::new();
+    let mut restart_delay = RESTART_DELAY;
+
+    loop {
+      info!("Listening for Broodminder events on {}.", adapter_name);
+
+      // When events are received by the BTLE stream, process them
+      while let Some(event) = events.next().await {
+        restart_delay = RESTART_DELAY;
+        let advertisement = advertisement_from(
+          &central,
+          &adapter_name,
+          event,
+          accepted_models.as_deref(),
+          &mut unnamed,
+        )
+        .await;
+        if let Some(advertisement) = advertisement {
+          // The device loop has gone away, so there's nobody left to listen
+          if advertisements.send(advertisement).await.is_err() {
+            return;
+          }
+        }
+      }
+
+      error!(
+        "Bluetooth events from {} stopped, restarting the scan",
+        adapter_name
+      );
+      loop {
+        tokio::time::sleep(restart_delay).await;
+        restart_delay = (restart_delay * 2).min(MAX_RESTART_DELAY);
+        match start_scan(&central).await {
+          Ok(restarted) => {
+            events = restarted;
+            break;
+          }
+          Err(error) => error!(
+            "Couldn't restart the scan on {}: {}, trying again in {}s",
+            adapter_name,
+            error,
+            restart_delay.as_secs()
+          ),
+        }
+      }
+    }
+  });
+
+  Ok(())
+}
+
+// The Advertisement for a bluetooth event, if it's a Broodminder advertisement
+async fn advertisement_from(
+  central: &Adapter,
+  adapter_name: &str,
+  event: CentralEvent,
+  accepted_models: Option<&[u8]>,
+  unnamed: &mut HashSet<String>,
+) -> Option<Advertisement> {
+  // Right now, we only care about the Data Advertisements from the Broodminder devices
+  let (id, manufacturer_data) = match event {
+    CentralEvent::ManufacturerDataAdvertisement {
+      id,
+      manufacturer_data,
+    } => (id, manufacturer_data),
+    _ => return None,
+  };
+
+  // Ensure we're only reading data from Broodminder devices
+  if !BroodminderDevice::is_broodminder(&manufacturer_data, accepted_models) {
+    return None;
+  }
+  // is_broodminder has checked the entry is there, but a lookup keeps that from being a panic if
+  // the two ever disagree
+  let data = match manufacturer_data.get(&MANUFACTURER_ID) {
+    Some(data) => data.clone(),
+    None => {
+      warn!(
+        "Skipping advertisement from {:?} without manufacturer {} data",
+        id, MANUFACTURER_ID
+      );
+      return None;
+    }
+  };
+  let peripheral = match central.peripheral(&id).await {
+    Ok(peripheral) => peripheral,
+    Err(error) => {
+      warn!(
+        "Skipping advertisement from unknown peripheral {:?}: {}",
+        id, error
+      );
+      return None;
+    }
+  };
+  let address = peripheral.address().to_string();
+
+  // Properties (and the local name in them) often aren't populated yet for the first
+  // advertisements of a device, the name usually arrives in a later scan response
+  let properties = match peripheral.properties().await {
+    Ok(properties) => properties,
+    Err(error) => {
+      debug!("Couldn't read properties of {}: {}", address, error);
+      None
+    }
+  };
+  let rssi = properties.as_ref().and_then(|properties| properties.rssi);
+  let local_name = match properties.and_then(|properties| properties.local_name) {
+    Some(local_name) => {
+      unnamed.remove(&address);
+      local_name
+    }
+    None => {
+      if unnamed.insert(address.clone()) {
+        info!(
+          "{} hasn't advertised its name yet, using its address until it does",
+          address
+        );
+      }
+      address.clone()
+    }
+  };
+
+  Some(Advertisement {
+    adapter: adapter_name.to_string(),
+    address,
+    local_name,
+    rssi,
+    data,
+    manufacturer_lengths: manufacturer_data
+      .iter()
+      .map(|(id, data)| (*id, data.len()))
+      .collect(),
+  })
+}
@@ -1,19 +1,704 @@
-use chrono::prelude::Utc;
+use crate::brood_flow_config::{
+  Configuration, MultiAdapterPolicy, TemperatureUnit, WeightOutOfRange,
+};
+#[cfg(feature = "mqtt")]
+use crate::brood_flow_config::{PayloadEncoding, TopicIdSource};
+#[cfg(feature = "mqtt")]
+use crate::device_names::UNKNOWN_DEVICE_ID;
+use crate::error::Error;
+#[cfg(feature = "mqtt")]
+use crate::payload_template;
+#[cfg(feature = "mqtt")]
+use crate::publisher::Publisher;
+#[cfg(feature = "mqtt")]
+use crate::topics::{self, TopicValues};
+use chrono::{SecondsFormat, TimeZone, Utc};
+#[cfg(feature = "mqtt")]
+use flate2::{write::GzEncoder, Compression};
+#[cfg(any(feature = "mqtt", feature = "rest"))]
 use json::object;
-use rumqttc::{AsyncClient, QoS};
-use std::collections::HashMap;
+use json::JsonValue;
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::fmt;
+#[cfg(feature = "mqtt")]
+use std::io::Write;
 
-#[derive(Debug, Default)]
+// Model number of the Broodminder outdoor weather station
+pub const WEATHER_MODEL: u8 = 60;
+
+// What a Broodminder model measures, so decoding and publishing branch on what a device has rather
+// than on model numbers scattered through the code. Every model has a temperature sensor
+#[derive(Debug, Clone)]
+pub struct ModelInfo {
+  pub model: u8, // The model number, data[0] of the advertisement
+  pub name: &'static str,
+  pub weight: bool,
+  pub pressure: bool,
+  // Byte indices (low, high) of an independent second temperature probe, for models that have one
+  pub second_probe: Option<(usize, usize)>,
+  // Byte index of the accelerometer's activity level, for models that sense swarming
+  pub activity: Option<usize>,
+}
+
+// The Broodminder devices this crate knows how to decode
+pub const MODELS: [ModelInfo; 6] = [
+  ModelInfo {
+    model: 47,
+    name: "T",
+    weight: false,
+    pressure: false,
+    second_probe: None,
+    activity: None,
+  },
+  ModelInfo {
+    model: 52,
+    name: "T2",
+    weight: false,
+    pressure: false,
+    // Two temperature probes on leads, e.g. one in the brood box and one in a super. The first is
+    // the usual realtime temperature. That the second is in bytes 10 and 11, encoded the same way,
+    // is a guess nobody has checked against a T2, so Probe2 is created disabled
+    second_probe: Some((10, 11)),
+    activity: None,
+  },
+  ModelInfo {
+    model: 56,
+    name: "TH",
+    weight: false,
+    pressure: false,
+    second_probe: None,
+    // Humidity isn't decoded. Where it sits in the advertisement and the manual's calibration of
+    // it are both unknown, and a guessed reading is worse than none for deciding how to winter a
+    // hive. Until then the TH is decoded as a T
+    activity: None,
+  },
+  ModelInfo {
+    model: 57,
+    name: "W",
+    weight: true,
+    pressure: false,
+    second_probe: None,
+    activity: None,
+  },
+  ModelInfo {
+    model: 63,
+    name: "T3",
+    weight: false,
+    pressure: false,
+    second_probe: None,
+    // An accelerometer reading how much the colony is moving, which jumps as it swarms. Byte 12
+    // is a guess, and so is its scale (0-255, so nobody knows what level a swarm reaches). Without
+    // a T3 capture, Activity and Swarm are created disabled
+    activity: Some(12),
+  },
+  ModelInfo {
+    model: WEATHER_MODEL,
+    name: "Weather",
+    weight: false,
+    pressure: true,
+    second_probe: None,
+    activity: None,
+  },
+];
+
+// Model numbers of every model in MODELS
+pub fn known_models() -> Vec<u8> {
+  MODELS.iter().map(|info| info.model).collect()
+}
+
+// The Home Assistant discovery component an entity is published as
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Component {
+  Sensor,
+  // On when the state_key value is on the `on_when` side of its threshold
+  BinarySensor { on_when: Threshold },
+}
+
+// When a binary sensor is on, from the reading it's derived from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Threshold {
+  Below(u8),
+  AtLeast(u8),
+}
+
+impl Threshold {
+  // The comparison in the value_template, e.g. "< 20"
+  fn comparison(&self) -> String {
+    match self {
+      Threshold::Below(value) => format!("< {}", value),
+      Threshold::AtLeast(value) => format!(">= {}", value),
+    }
+  }
+}
+
+impl Component {
+  // The {component} part of the config topic
+  pub fn as_str(&self) -> &'static str {
+    match self {
+      Component::Sensor => "sensor",
+      Component::BinarySensor { .. } => "binary_sensor",
+    }
+  }
+}
+
+// A Home Assistant entity published for a device. Each one gets its own discovery config message,
+// and reads its value from `state_key` in the device's shared state message
+#[derive(Debug, Clone, PartialEq)]
+pub struct Sensor {
+  pub id: &'static str, // Suffix of the entity name and unique_id, e.g. "temperature"
+  pub kind: &'static str, // What publish_sensors filters on, e.g. "temperature" for every temperature
+  pub state_key: &'static str, // Key in the state message, e.g. "temperature_c"
+  pub topic: &'static str, // The {sensor} part of the config topic, e.g. "Temp"
+  pub component: Component,
+  pub device_class: Option<&'static str>,
+  pub unit: &'static str, // Unused for binary sensors
+  // Diagnostic entities are disabled in HA unless enable_diagnostics is set, and listed under
+  // Diagnostic rather than with the readings unless categorize_diagnostics is false
+  pub diagnostic: bool,
+  // Decoded from a layout nobody has checked against a real unit (see MODELS), so disabled in HA
+  // unless enable_unconfirmed_sensors is set
+  pub unconfirmed: bool,
+}
+
+const TEMPERATURE: Sensor = Sensor {
+  id: "temperature",
+  kind: "temperature",
+  state_key: "temperature_c",
+  topic: "Temp",
+  component: Component::Sensor,
+  device_class: Some("temperature"),
+  unit: "°C",
+  diagnostic: false,
+  unconfirmed: false,
+};
+const TEMPERATURE_F: Sensor = Sensor {
+  id: "temperature_f",
+  kind: "temperature",
+  state_key: "temperature_f",
+  topic: "TempF",
+  component: Component::Sensor,
+  device_class: Some("temperature"),
+  unit: "°F",
+  diagnostic: false,
+  unconfirmed: false,
+};
+// The realtime temperature with temperature_unit: fahrenheit, the same entity reading the °F value
+const TEMPERATURE_IN_F: Sensor = Sensor {
+  state_key: "temperature_f",
+  unit: "°F",
+  ..TEMPERATURE
+};
+// The sensor's own smoothed temperature, which changes more slowly than the realtime one
+const AGGREGATED_TEMPERATURE: Sensor = Sensor {
+  id: "aggregated_temperature",
+  kind: "temperature",
+  state_key: "aggregated_temperature_c",
+  topic: "AggTemp",
+  component: Component::Sensor,
+  device_class: Some("temperature"),
+  unit: "°C",
+  diagnostic: false,
+  unconfirmed: false,
+};
+const AGGREGATED_TEMPERATURE_F: Sensor = Sensor {
+  state_key: "aggregated_temperature_f",
+  unit: "°F",
+  ..AGGREGATED_TEMPERATURE
+};
+const TEMPERATURE_PROBE1: Sensor = Sensor {
+  id: "temperature_probe1",
+  kind: "temperature",
+  state_key: "temperature_probe1_c",
+  topic: "Probe1",
+  component: Component::Sensor,
+  device_class: Some("temperature"),
+  unit: "°C",
+  diagnostic: false,
+  unconfirmed: false,
+};
+const TEMPERATURE_PROBE2: Sensor = Sensor {
+  id: "temperature_probe2",
+  kind: "temperature",
+  state_key: "temperature_probe2_c",
+  topic: "Probe2",
+  component: Component::Sensor,
+  device_class: Some("temperature"),
+  unit: "°C",
+  diagnostic: false,
+  unconfirmed: true,
+};
+const WEIGHT: Sensor = Sensor {
+  id: "weight",
+  kind: "weight",
+  state_key: "weight_kg",
+  topic: "Weight",
+  component: Component::Sensor,
+  device_class: Some("weight"),
+  unit: "kg",
+  diagnostic: false,
+  unconfirmed: false,
+};
+const WEIGHT_LBS: Sensor = Sensor {
+  id: "weight_lbs",
+  kind: "weight",
+  state_key: "weight_lbs",
+  topic: "WeightLbs",
+  component: Component::Sensor,
+  device_class: Some("weight"),
+  unit: "lb",
+  diagnostic: false,
+  unconfirmed: false,
+};
+const PRESSURE: Sensor = Sensor {
+  id: "pressure",
+  kind: "pressure",
+  state_key: "pressure_hpa",
+  topic: "Pressure",
+  component: Component::Sensor,
+  device_class: Some("atmospheric_pressure"),
+  unit: "hPa",
+  diagnostic: false,
+  unconfirmed: true,
+};
+
+// The temperature minus the ambient_device_id device's. Not a temperature itself, so it has no
+// device class for HA to convert from °C as if it were one
+const TEMPERATURE_DELTA: Sensor = Sensor {
+  id: "temp_delta",
+  kind: "temp_delta",
+  state_key: "temp_delta_c",
+  topic: "TempDelta",
+  component: Component::Sensor,
+  device_class: None,
+  unit: "°C",
+  diagnostic: false,
+  unconfirmed: false,
+};
+
+// The accelerometer's activity level, on models that have one
+const ACTIVITY: Sensor = Sensor {
+  id: "activity",
+  kind: "activity",
+  state_key: "activity",
+  topic: "Activity",
+  component: Component::Sensor,
+  device_class: None,
+  unit: "",
+  diagnostic: false,
+  unconfirmed: true,
+};
+
+// On while the activity level is at least swarm_threshold. It only exists with a threshold, so
+// it isn't in SENSORS
+pub fn swarm_sensor(threshold: u8) -> Sensor {
+  Sensor {
+    id: "swarm",
+    kind: "activity",
+    state_key: "activity",
+    topic: "Swarm",
+    component: Component::BinarySensor {
+      on_when: Threshold::AtLeast(threshold),
+    },
+    device_class: Some("problem"),
+    unit: "",
+    diagnostic: false,
+    unconfirmed: true,
+  }
+}
+
+// Battery percentages below 20 show as a low battery problem in HA
+const LOW_BATTERY: Sensor = Sensor {
+  id: "battery_low",
+  kind: "battery",
+  state_key: "battery_percent",
+  topic: "BatteryLow",
+  component: Component::BinarySensor {
+    on_when: Threshold::Below(20),
+  },
+  device_class: Some("battery"),
+  unit: "",
+  diagnostic: false,
+  unconfirmed: false,
+};
+
+pub const LBS_PER_KG: f32 = 2.204623;
+
+const RSSI: Sensor = Sensor {
+  id: "rssi",
+  kind: "rssi",
+  state_key: "rssi",
+  topic: "Rssi",
+  component: Component::Sensor,
+  device_class: Some("signal_strength"),
+  unit: "dBm",
+  diagnostic: true,
+  unconfirmed: false,
+};
+
+// The model name, e.g. "Broodminder-W", published once with the config, see publish_model
+const MODEL: Sensor = Sensor {
+  id: "model",
+  kind: "model",
+  state_key: "model",
+  topic: "Model",
+  component: Component::Sensor,
+  device_class: None,
+  unit: "",
+  diagnostic: true,
+  unconfirmed: false,
+};
+
+// The recent RSSI as a 0-100 percentage, see publish_signal_quality
+const SIGNAL_QUALITY: Sensor = Sensor {
+  id: "signal_quality",
+  kind: "signal_quality",
+  state_key: "signal_quality_percent",
+  topic: "SignalQuality",
+  component: Component::Sensor,
+  device_class: None,
+  unit: "%",
+  diagnostic: true,
+  unconfirmed: false,
+};
+
+// How many times the device has restarted since brood-flow started, see publish_resets
+const RESETS: Sensor = Sensor {
+  id: "resets",
+  kind: "resets",
+  state_key: "resets",
+  topic: "Resets",
+  component: Component::Sensor,
+  device_class: None,
+  unit: "",
+  diagnostic: true,
+  unconfirmed: false,
+};
+
+// When the device was last heard, as an ISO 8601 time, see publish_last_seen
+const LAST_SEEN: Sensor = Sensor {
+  id: "last_seen",
+  kind: "last_seen",
+  state_key: "last_seen",
+  topic: "LastSeen",
+  component: Component::Sensor,
+  device_class: Some("timestamp"),
+  unit: "",
+  diagnostic: false,
+  unconfirmed: false,
+};
+
+// Where one of a device's entities was published
+#[cfg(feature = "mqtt")]
+#[derive(Debug, Clone, PartialEq)]
+pub struct DiscoveryEntry {
+  pub sensor_id: String, // Sensor::id, e.g. "temperature"
+  pub config_topic: String,
+  pub unique_id: String,
+}
+
+// Every sensor, for looking them up by state key or kind
+pub const SENSORS: [Sensor; 17] = [
+  TEMPERATURE,
+  TEMPERATURE_F,
+  AGGREGATED_TEMPERATURE,
+  AGGREGATED_TEMPERATURE_F,
+  TEMPERATURE_PROBE1,
+  TEMPERATURE_PROBE2,
+  TEMPERATURE_DELTA,
+  WEIGHT,
+  WEIGHT_LBS,
+  PRESSURE,
+  ACTIVITY,
+  LOW_BATTERY,
+  RSSI,
+  SIGNAL_QUALITY,
+  RESETS,
+  LAST_SEEN,
+  MODEL,
+];
+
+// The parser reads up to data[20] (the realtime weight bytes)
+const MIN_PAYLOAD_LEN: usize = 21;
+
+// How often each device's discovery config is resent
+#[cfg(feature = "mqtt")]
+const CONFIG_INTERVAL_MS: i64 = 3600000;
+
+// Limits for drop_implausible_packets. The temperatures are the sensors' rated range, with room to
+// spare for future firmware
+const MAX_MAJOR_VERSION: u8 = 20;
+const MIN_TEMPERATURE_C: f32 = -40.0;
+const MAX_TEMPERATURE_C: f32 = 85.0;
+// Fresh batteries read a few percent over 100 (see clamp_battery), well beyond that is corruption
+const MAX_BATTERY_PERCENT: u8 = 110;
+
+// The elapsed counter going back by no more than this is taken as an older advertisement arriving
+// late (e.g. via another adapter) rather than a reset. Going back from within this of the top of
+// its range is the counter wrapping around
+const ELAPSED_REORDER_TICKS: u16 = 2;
+const ELAPSED_WRAP_TICKS: u16 = 256;
+
+// The signal quality is worked out from the mean of this many recent RSSI samples, so a single
+// faded advertisement doesn't swing it
+const SIGNAL_QUALITY_SAMPLES: usize = 10;
+
+// When a device is heard by more than one adapter, readings from a different adapter within this
+// window only replace the current one if their signal is at least as strong
+const ADAPTER_DEDUP_WINDOW_MS: i64 = 10000;
+
+// The readings sent in a device's state message. Serialized with json for Home Assistant, or with
+// rmp-serde when payload_encoding is msgpack. Readings a model doesn't have (or that aren't
+// published) are None and left out of the message
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct StateReading {
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub temperature_c: Option<f64>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub temperature_f: Option<f64>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub aggregated_temperature_c: Option<f64>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub aggregated_temperature_f: Option<f64>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub battery_percent: Option<f64>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub temperature_probe1_c: Option<f64>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub temperature_probe2_c: Option<f64>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub temp_delta_c: Option<f64>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub weight_kg: Option<f64>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub weight_lbs: Option<f64>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub pressure_hpa: Option<f64>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub activity: Option<f64>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub rssi: Option<f64>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub signal_quality_percent: Option<f64>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub resets: Option<f64>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub last_seen: Option<String>, // Not a number, so it isn't in fields()
+  // The sensors configured under models, by id
+  #[serde(flatten)]
+  pub custom: BTreeMap<&'static str, f64>,
+}
+
+impl StateReading {
+  // Whether any reading differs, for publish_on_change_only, by at least min_change(key) when
+  // that's set. The RSSI moves with every advertisement (and the signal quality with it), so on
+  // its own it doesn't count as a change
+  pub fn changed_from(&self, other: &StateReading, min_change: impl Fn(&str) -> f64) -> bool {
+    let moved = |key: &str, value: Option<f64>, other: Option<f64>| match (value, other) {
+      // The readings are rounded, so e.g. 24.5 - 24.4 can come out a hair under 0.1
+      (Some(value), Some(other)) if min_change(key) > 0.0 => {
+        (value - other).abs() >= min_change(key) - 1e-9
+      }
+      (value, other) => value != other,
+    };
+    let fields_moved =
+      self
+        .fields()
+        .iter()
+        .zip(other.fields().iter())
+        .any(|((key, value), (_, other))| {
+          !["rssi", "signal_quality_percent"].contains(key) && moved(key, *value, *other)
+        });
+    fields_moved
+      || self.custom.keys().chain(other.custom.keys()).any(|key| {
+        moved(
+          key,
+          self.custom.get(key).copied(),
+          other.custom.get(key).copied(),
+        )
+      })
+  }
+
+  // Every reading the message has with its key, the configured sensors' last
+  pub fn readings(&self) -> Vec<(&'static str, f64)> {
+    self
+      .fields()
+      .into_iter()
+      .filter_map(|(key, value)| Some((key, value?)))
+      .chain(self.custom.iter().map(|(key, value)| (*key, *value)))
+      .collect()
+  }
+
+  // Every built in reading with its key in the state message, in message order
+  pub fn fields(&self) -> [(&'static str, Option<f64>); 15] {
+    [
+      ("temperature_c", self.temperature_c),
+      ("temperature_f", self.temperature_f),
+      ("aggregated_temperature_c", self.aggregated_temperature_c),
+      ("aggregated_temperature_f", self.aggregated_temperature_f),
+      ("battery_percent", self.battery_percent),
+      ("temperature_probe1_c", self.temperature_probe1_c),
+      ("temperature_probe2_c", self.temperature_probe2_c),
+      ("temp_delta_c", self.temp_delta_c),
+      ("weight_kg", self.weight_kg),
+      ("weight_lbs", self.weight_lbs),
+      ("pressure_hpa", self.pressure_hpa),
+      ("activity", self.activity),
+      ("rssi", self.rssi),
+      ("signal_quality_percent", self.signal_quality_percent),
+      ("resets", self.resets),
+    ]
+  }
+
+  fn fields_mut(&mut self) -> [(&'static str, &mut Option<f64>); 15] {
+    [
+      ("temperature_c", &mut self.temperature_c),
+      ("temperature_f", &mut self.temperature_f),
+      (
+        "aggregated_temperature_c",
+        &mut self.aggregated_temperature_c,
+      ),
+      (
+        "aggregated_temperature_f",
+        &mut self.aggregated_temperature_f,
+      ),
+      ("battery_percent", &mut self.battery_percent),
+      ("temperature_probe1_c", &mut self.temperature_probe1_c),
+      ("temperature_probe2_c", &mut self.temperature_probe2_c),
+      ("temp_delta_c", &mut self.temp_delta_c),
+      ("weight_kg", &mut self.weight_kg),
+      ("weight_lbs", &mut self.weight_lbs),
+      ("pressure_hpa", &mut self.pressure_hpa),
+      ("activity", &mut self.activity),
+      ("rssi", &mut self.rssi),
+      ("signal_quality_percent", &mut self.signal_quality_percent),
+      ("resets", &mut self.resets),
+    ]
+  }
+
+  // Adds another reading field by field, for averaging
+  fn add(&mut self, other: &StateReading) {
+    for ((_, sum), (_, value)) in self.fields_mut().into_iter().zip(other.fields()) {
+      *sum = match (*sum, value) {
+        (Some(sum), Some(value)) => Some(sum + value),
+        (sum, value) => sum.or(value),
+      };
+    }
+    for (key, value) in &other.custom {
+      *self.custom.entry(key).or_default() += value;
+    }
+  }
+
+  // Applies f to every reading
+  fn map(&self, f: impl Fn(f64) -> f64) -> StateReading {
+    self.map_keyed(|_, value| f(value))
+  }
+
+  // Applies f to every reading, with its key
+  fn map_keyed(&self, f: impl Fn(&str, f64) -> f64) -> StateReading {
+    let mut mapped = self.clone();
+    for (key, value) in mapped.fields_mut() {
+      *value = value.map(|value| f(key, value));
+    }
+    for (key, value) in mapped.custom.iter_mut() {
+      *value = f(key, *value);
+    }
+    mapped
+  }
+
+  // Drops the readings of every sensor kind (see Sensor) that isn't published
+  pub fn retain_kinds(&mut self, published: impl Fn(&str) -> bool) {
+    for (key, value) in self.fields_mut() {
+      let kind = SENSORS.iter().find(|sensor| sensor.state_key == key);
+      if kind.is_some_and(|sensor| !published(sensor.kind)) {
+        *value = None;
+      }
+    }
+    if !published(LAST_SEEN.kind) {
+      self.last_seen = None;
+    }
+    // A configured sensor's kind is its id
+    self.custom.retain(|key, _| published(key));
+  }
+
+  pub fn to_json(&self) -> JsonValue {
+    let mut state_message = JsonValue::new_object();
+    for (key, value) in self.readings() {
+      state_message[key] = value.into();
+    }
+    if let Some(last_seen) = &self.last_seen {
+      state_message["last_seen"] = last_seen.clone().into();
+    }
+    state_message
+  }
+}
+
+// Sums a device's readings over a downsample_secs window, so the mean of the window can be
+// published rather than whichever reading happened to arrive when the window ended
+#[derive(Debug, Default, Clone)]
+struct Downsampler {
+  window_start: i64, // Millisecond epoch time of the first reading in the window
+  // Readings in the window by key, as one can be missing from some of them (e.g. a weight out of
+  // range)
+  counts: BTreeMap<&'static str, u32>,
+  sum: Option<StateReading>,
+}
+
+impl Downsampler {
+  // Adds a reading, returning the mean of the window once it has lasted window_ms
+  fn add(&mut self, reading: StateReading, now: i64, window_ms: i64) -> Option<StateReading> {
+    for (key, _) in reading.readings() {
+      *self.counts.entry(key).or_default() += 1;
+    }
+    match &mut self.sum {
+      Some(sum) => sum.add(&reading),
+      None => {
+        self.window_start = now;
+        self.sum = Some(reading);
+      }
+    }
+
+    if now - self.window_start < window_ms {
+      return None;
+    }
+
+    let counts = std::mem::take(&mut self.counts);
+    self
+      .sum
+      .take()
+      .map(|sum| sum.map_keyed(|key, value| value / counts.get(key).copied().unwrap_or(1) as f64))
+  }
+}
+
+// Corrections set at runtime through the REST API (see rest_port), on top of the configuration.
+// They last until brood-flow restarts
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Calibration {
+  pub temperature_offset_c: f64, // Added to the main sensor's temperatures, not the probes
+  pub weight_offset_kg: f64,
+}
+
+// A freshly decoded advertisement, broadcast from the BLE loop to every output (MQTT, ...)
+#[derive(Debug, Clone)]
+pub struct Reading {
+  pub device: BroodminderDevice,
+}
+
+#[derive(Debug, Default, Clone)]
+#[allow(dead_code)] // Not every decoded byte is published yet
 pub struct BroodminderDevice {
-  pub device_id: String,
+  pub device_id: String, // Used for display and topics, the local name made unique if need be
+  pub address: String,   // The MAC address, which identifies the physical sensor
+  pub local_name: String, // The name the sensor advertises, its Broodminder id, e.g. "47:01:01"
   pub model: u8,
+  info: Option<ModelInfo>, // What the model measures, see Configuration::model_info
   pub minor_version: u8,
   pub major_version: u8,
   pub realtime_temp1: u8, // Realtime temperature can update every advertisement and is not aggregated
   pub battery_percent: u8,
   pub elapsed1: u8, // How many 'ticks' have passed, I believe this is an internal aggregation/smoothing mechanism
-  pub elapsed2: u8,
-  pub temp1: u8, // Temperature updates every 'elapsed' tick, and is an aggregated value
+  pub elapsed2: u8, // It starts again from 0 when the sensor restarts, which is how resets are counted
+  pub temp1: u8,    // Temperature updates every 'elapsed' tick, and is an aggregated value
   pub temp2: u8,
   pub realtime_temp2: u8, // Realtime temp uses two bytes and some math to calculate
   pub realtime_weight1: u8, // Two bytes representing the total weight of the scale
@@ -22,70 +707,174 @@ pub struct BroodminderDevice {
   pub weight_l2: u8,
   pub weight_r1: u8,
   pub weight_r2: u8,
+  pub pressure1: u8, // Two bytes representing barometric pressure (weather station only)
+  pub pressure2: u8,
+  pub raw_hex: String, // The whole advertisement hex encoded, for reverse engineering new models
+  pub payload: Vec<u8>, // The whole advertisement, for the sensors configured under models
+  pub manufacturer_lengths: BTreeMap<u16, usize>, // Data length by manufacturer id, see publish_manufacturer_data
   // Broodminder devices also report left and right weight independently, but that seems
   // like overkill for this application. If someone has a need, it wouldn't be difficult to add
 
-  // Calculated sensor values
+  // Calculated sensor values. Readings that only some models have are None for the other models
   pub realtime_temperature_c: f32,
   pub realtime_temperature_f: f32,
   pub temperature_c: f32,
   pub temperature_f: f32,
-  pub realtime_weight_kg: f32,
-  pub realtime_weight_lbs: f32, // Home Assistant doesn't autotranslate kgs to lbs for some reason?
-  pub weight_l_kgs: f32, // TODO: It's kinda silly to store both of these, right? Like, I can just store one unit
-  pub weight_r_kgs: f32, // and convert at reporting time.
-  pub weight_l_lbs: f32,
-  pub weight_r_lbs: f32,
+  pub temperature_probe1_c: Option<f32>, // Only for models with two independent probes
+  pub temperature_probe2_c: Option<f32>,
+  pub realtime_weight_kg: Option<f32>,
+  pub realtime_weight_lbs: Option<f32>, // Home Assistant doesn't autotranslate kgs to lbs for some reason?
+  pub weight_l_kgs: Option<f32>, // TODO: It's kinda silly to store both of these, right? Like, I can just store one unit
+  pub weight_r_kgs: Option<f32>, // and convert at reporting time.
+  pub weight_l_lbs: Option<f32>,
+  pub weight_r_lbs: Option<f32>,
+  pub pressure_hpa: Option<f32>,
+  pub activity: Option<u8>, // Accelerometer activity level, higher when the colony is restless
+  pub resets: u32, // Times the elapsed counter went back since brood-flow first heard the device
+  ambient_temperature_c: Option<f32>, // The ambient_device_id device's temperature, see set_ambient_temperature
+  pub calibration: Calibration,
+  weight_out_of_range: bool, // Whether the calibrated weight was outside weight_min_kg..weight_max_kg, see check_weight_range
+
+  // Where the current reading came from
+  pub adapter: String, // The adapter that heard the latest accepted advertisement
+  pub rssi: Option<i16>,
+  rssi_history: VecDeque<i16>, // The latest SIGNAL_QUALITY_SAMPLES RSSI samples, oldest first
+  last_seen: i64,              // Millisecond epoch time of the latest accepted advertisement
+  advertisement_interval_ms: Option<i64>, // Moving average of the gaps between accepted advertisements
+  history: VecDeque<(i64, StateReading)>, // Recent unrounded readings and when, oldest first, up to history_len
 
   // Millisecond epoch time since last messages were sent for this device, for rate limiting
   last_config_sent: i64,
   last_state_sent: i64,
+  config_published: bool, // Whether config has been sent at all, for require_config_before_state
+  last_published: Option<(StateReading, i64)>, // The latest published reading and when, for publish_on_change_only
+  downsampler: Downsampler,                    // Only used with downsample_secs
+}
+
+// A one line summary for info logs, e.g. "47:01:01 (T) 24.50°C, battery 88%". The Debug output has
+// every field and is kept for debug logs
+impl fmt::Display for BroodminderDevice {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self.model_info() {
+      Some(info) => write!(f, "{} ({})", self.device_id, info.name)?,
+      None => write!(f, "{} (model {})", self.device_id, self.model)?,
+    }
+    write!(
+      f,
+      " {:.2}°C, battery {}%",
+      self.realtime_temperature_c, self.battery_percent
+    )
+  }
 }
 
-// TODO: Cleanup logging, use a consistent approach to what should and shouldn't be logged
+// The manufacturer specific data id Broodminder devices advertise under, 0x028D
+pub const MANUFACTURER_ID: u16 = 653;
 
 impl BroodminderDevice {
-  // Broodminder devices will broadcast 0x028D (653) as their manufacturer specific data id
-  pub fn is_broodminder(data: &HashMap<u16, Vec<u8>>) -> bool {
-    data.contains_key(&653)
+  // Broodminder devices will broadcast 0x028D (653) as their manufacturer specific data id.
+  // The payload also has to be long enough for the parser to read every byte it indexes,
+  // otherwise build_broodminder_device/update would refuse it.
+  // If accepted_models is given, the model byte (data[0]) must also be one of them, which guards
+  // against other devices that happen to use 653
+  pub fn is_broodminder(data: &HashMap<u16, Vec<u8>>, accepted_models: Option<&[u8]>) -> bool {
+    let payload = match data.get(&MANUFACTURER_ID) {
+      Some(payload) if payload.len() >= MIN_PAYLOAD_LEN => payload,
+      _ => return false,
+    };
+
+    match accepted_models {
+      Some(models) if !models.contains(&payload[0]) => {
+        debug!(
+          "Skipping manufacturer 653 advertisement with unknown model {}",
+          payload[0]
+        );
+        false
+      }
+      _ => true,
+    }
+  }
+
+  // Why an advertisement can't be a genuine reading, if it can't. Broodminder advertisements have no
+  // checksum, so a packet corrupted over the air would otherwise decode to garbage. This checks
+  // what every model has in common, readings outside what the sensors can measure
+  pub fn implausible(data: &[u8]) -> Option<String> {
+    if data.len() < MIN_PAYLOAD_LEN {
+      return Some(format!("only {} bytes", data.len()));
+    }
+    // Released firmware is 1.x up to 4.x so far
+    let major_version = data[2];
+    if !(1..=MAX_MAJOR_VERSION).contains(&major_version) {
+      return Some(format!("firmware major version {}", major_version));
+    }
+    if data[4] > MAX_BATTERY_PERCENT {
+      return Some(format!("battery {}%", data[4]));
+    }
+    let temperatures = [
+      (
+        "realtime temperature",
+        decode_temperature_c(data[3], data[9]),
+      ),
+      ("temperature", decode_temperature_c(data[7], data[8])),
+    ];
+    for (name, temperature_c) in temperatures {
+      if !(MIN_TEMPERATURE_C..=MAX_TEMPERATURE_C).contains(&temperature_c) {
+        return Some(format!("{} {:.2}°C", name, temperature_c));
+      }
+    }
+    None
+  }
+
+  // Decodes the advertisement with the built in layout of its model
+  pub fn build_broodminder_device(data: &[u8]) -> Result<Self, Error> {
+    check_payload_len(data)?;
+    let info = MODELS.iter().find(|info| info.model == data[0]).cloned();
+    Self::build_with_model_info(data, info)
   }
 
-  pub fn build_broodminder_device(data: &Vec<u8>) -> Self {
-    Self {
+  // Decodes the advertisement as `info` says the model measures, e.g. from
+  // Configuration::model_info. None for a model brood-flow doesn't know
+  pub fn build_with_model_info(data: &[u8], info: Option<ModelInfo>) -> Result<Self, Error> {
+    check_payload_len(data)?;
+    let mut device = Self {
       device_id: "(unknown)".to_string(),
       model: data[0],
+      info,
       minor_version: data[1],
       major_version: data[2],
-      realtime_temp1: data[3],
-      battery_percent: data[4],
-      elapsed1: data[5],
-      elapsed2: data[6],
-      temp1: data[7],
-      temp2: data[8],
-      realtime_temp2: data[9],
-      realtime_weight1: data[19],
-      realtime_weight2: data[20],
-      // Pulled from the Broodminder manual
-      realtime_temperature_c: (256.0 * data[9] as f32 + data[3] as f32 - 5000.0) / 100.0,
-      realtime_temperature_f: ((256.0 * data[9] as f32 + data[3] as f32 - 5000.0) / 100.0) * 9.0
-        / 5.0
-        + 32.0,
-      temperature_c: (256.0 * data[8] as f32 + data[7] as f32 - 5000.0) / 100.0,
-      temperature_f: ((256.0 * data[8] as f32 + data[7] as f32 - 5000.0) / 100.0) * 9.0 / 5.0
-        + 32.0,
-
-      realtime_weight_kg: ((256.0 * data[20] as f32) - data[19] as f32 - 32767.0) as f32 / 100.0,
-      realtime_weight_lbs: 2.204623
-        * ((256.0 * data[20] as f32) - data[19] as f32 - 32767.0) as f32
-        / 100.0,
       last_config_sent: 0,
+      config_published: false,
+      last_published: None,
       last_state_sent: 0,
-    }
+      ..Default::default()
+    };
+    device.update(data)?;
+    Ok(device)
   }
 
-  // Take in a Data Advertisement and parse it into fields, updating in place
-  pub fn update(&mut self, data: &Vec<u8>) {
+  // Take in a Data Advertisement and parse it into fields, updating in place. A payload too short
+  // to decode leaves the device as it was
+  pub fn update(&mut self, data: &[u8]) -> Result<(), Error> {
+    check_payload_len(data)?;
+    let info = self.info.clone();
+    self.update_with_model(data, info.as_ref());
+    Ok(())
+  }
+
+  fn update_with_model(&mut self, data: &[u8], info: Option<&ModelInfo>) {
     debug!("Update: {:?}", data);
+    // The first advertisement has no earlier counter to compare with
+    let elapsed = u16::from_le_bytes([data[5], data[6]]);
+    if !self.raw_hex.is_empty() && is_reset(self.elapsed(), elapsed) {
+      self.resets += 1;
+      warn!(
+        "{} restarted (elapsed counter went from {} to {}), it may have lost power",
+        self.device_id,
+        self.elapsed(),
+        elapsed
+      );
+    }
+    self.raw_hex = data.iter().map(|byte| format!("{:02x}", byte)).collect();
+    self.payload = data.to_vec();
     self.realtime_temp1 = data[3];
     self.battery_percent = data[4];
     self.elapsed1 = data[5];
@@ -96,173 +885,2459 @@ impl BroodminderDevice {
     self.realtime_weight1 = data[19];
     self.realtime_weight2 = data[20];
 
-    self.realtime_temperature_c = (256.0 * data[9] as f32 + data[3] as f32 - 5000.0) / 100.0;
-    self.realtime_temperature_f =
-      ((256.0 * data[9] as f32 + data[3] as f32 - 5000.0) / 100.0) * 9.0 / 5.0 + 32.0;
-    self.temperature_c = (256.0 * data[8] as f32 + data[7] as f32 - 5000.0) / 100.0;
-    self.temperature_f =
-      ((256.0 * data[8] as f32 + data[7] as f32 - 5000.0) / 100.0) * 9.0 / 5.0 + 32.0;
-    self.realtime_weight_kg =
-      ((256.0 * data[20] as f32) - data[19] as f32 - 32767.0) as f32 / 100.0;
-    self.realtime_weight_lbs =
-      2.204623 * ((256.0 * data[20] as f32) - data[19] as f32 - 32767.0) as f32 / 100.0;
-  }
-
-  // Keeping this method here for now as documentation for how to send messages that remove devices from
-  // HomeAssistant, should that become necessary in the future.
-  #[allow(unused_mut, dead_code)] // Client needs to be mutable to send messages for some reason
-  pub fn send_delete_messages(&self, mut _client: AsyncClient) {
-    // Home Assistant will delete any device it receives an empty config message for
-    // The topic must conform to:
-    //   <discovery_prefix>/<component>/[<node_id>/]<object_id>/config
-    //   homeassistant/sensor/47:00:00/config
-    // A JSON payload must be empty
-  }
+    self.realtime_temperature_c = decode_temperature_c(data[3], data[9]);
+    self.realtime_temperature_f = self.realtime_temperature_c * 9.0 / 5.0 + 32.0;
+    self.temperature_c = decode_temperature_c(data[7], data[8]);
+    self.temperature_f = self.temperature_c * 9.0 / 5.0 + 32.0;
 
-  // Sends a HomeAssistant compatible MQTT message with an update on the state of the device
-  // (e.g. the current temperature, humidity, weight, or other data as appropriate)
-  #[allow(unused_mut)] // Client needs to be mutable to send messages for some reason
-  pub fn send_state_message(&mut self, mut client: AsyncClient) {
-    if self.device_id == "00:00:00" {
-      return ();
+    let info = match info {
+      Some(info) => info,
+      None => return,
+    };
+
+    // Models with two independent probes report the first one as the usual realtime temperature
+    if let Some((low, high)) = info.second_probe {
+      self.temperature_probe1_c = Some(self.realtime_temperature_c);
+      self.temperature_probe2_c = Some(decode_temperature_c(data[low], data[high]));
     }
 
-    // State topic should be whatever is set in 'state_topic' in the config message
-    // e.g.
-    // homeassistant/sensor/47:00:00/state
-    // And should contain a json object that can be parsed by the 'value_template'
-    // See: https://www.home-assistant.io/docs/configuration/templating/#processing-incoming-data
-    if Utc::now().timestamp_millis() - self.last_state_sent > 30000 {
-      // No more than 1 per 30s
-      // TODO: Magic numbers should be managed by config
-      info!("Publishing state via MQTT for {:?}", self.device_id);
+    // Only scales have weight, the bytes are garbage on other models
+    if info.weight {
+      let weight_kg = decode_weight_kg(data[19], data[20]);
+      self.realtime_weight_kg = Some(weight_kg);
+      self.realtime_weight_lbs = Some(LBS_PER_KG * weight_kg);
+    }
 
-      let simple_id = self.device_id.clone().replace(":", "");
+    // The weather station reports barometric pressure where other models have their (unused)
+    // left/right weight bytes. Bytes 15 and 16 as little endian tenths of a hPa is a guess that
+    // hasn't been checked against a station's capture, so Pressure is created disabled
+    if info.pressure {
+      self.pressure1 = data[15];
+      self.pressure2 = data[16];
+      self.pressure_hpa = Some((256.0 * data[16] as f32 + data[15] as f32) / 10.0);
+    }
 
-      let mut state_message = object! {
-        temperature_c: self.realtime_temperature_c,
-      };
+    if let Some(byte) = info.activity {
+      self.activity = Some(data[byte]);
+    }
+  }
 
-      // Scales (model number 57) emit a weight value as well
-      if self.model == 57 {
-        state_message["weight_lbs"] = self.realtime_weight_lbs.into();
-      }
+  // The elapsed ticks as the sensor counts them
+  pub fn elapsed(&self) -> u16 {
+    u16::from_le_bytes([self.elapsed1, self.elapsed2])
+  }
 
-      let state_topic = format!("homeassistant/sensor/BM{}/state", simple_id);
-      info!("Publishing: {} to {}", state_message.dump(), state_topic);
+  // What this device's model measures, None for models brood-flow doesn't know
+  pub fn model_info(&self) -> Option<&ModelInfo> {
+    self.info.as_ref()
+  }
 
-      tokio::task::spawn(async move {
-        match client
-          .publish(state_topic, QoS::AtLeastOnce, false, state_message.dump())
-          .await
-        {
-          Err(error) => info!("Error: {:?}", error),
-          Ok(_) => info!("Sent state!"),
+  // The entities this device publishes to Home Assistant
+  pub fn sensors(&self, settings: &Configuration) -> Vec<Sensor> {
+    let mut sensors = Vec::new();
+
+    if self.model_info().is_some() {
+      // Fahrenheit is reported alongside Celsius in the same state message, so the unit just
+      // picks which key an entity reads
+      match settings.temperature_unit {
+        TemperatureUnit::Celsius => {
+          sensors.push(TEMPERATURE);
+          if settings.publish_fahrenheit {
+            sensors.push(TEMPERATURE_F);
+          }
         }
-      });
+        TemperatureUnit::Fahrenheit => sensors.push(TEMPERATURE_IN_F),
+      }
+      match settings.aggregated_temperature_unit {
+        Some(TemperatureUnit::Celsius) => sensors.push(AGGREGATED_TEMPERATURE),
+        Some(TemperatureUnit::Fahrenheit) => sensors.push(AGGREGATED_TEMPERATURE_F),
+        None => {}
+      }
+      sensors.push(LOW_BATTERY);
+    }
+    if self.temperature_probe1_c.is_some() {
+      sensors.push(TEMPERATURE_PROBE1);
+    }
+    if self.temperature_probe2_c.is_some() {
+      sensors.push(TEMPERATURE_PROBE2);
+    }
+    if self.model_info().is_some()
+      && settings.ambient_device_id.is_some()
+      && !self.is_ambient(settings)
+    {
+      sensors.push(TEMPERATURE_DELTA);
+    }
+    if self.realtime_weight_kg.is_some() {
+      sensors.push(WEIGHT);
+      if settings.publish_both_weight_units {
+        sensors.push(WEIGHT_LBS);
+      }
+    }
+    if self.pressure_hpa.is_some() {
+      sensors.push(PRESSURE);
+    }
+    if self.activity.is_some() {
+      sensors.push(ACTIVITY);
+      if let Some(threshold) = settings.swarm_threshold {
+        sensors.push(swarm_sensor(threshold));
+      }
+    }
+    if self.rssi.is_some() {
+      sensors.push(RSSI);
+    }
+    if settings.publish_signal_quality && self.rssi.is_some() {
+      sensors.push(SIGNAL_QUALITY);
+    }
+    if settings.publish_resets && self.model_info().is_some() {
+      sensors.push(RESETS);
+    }
+    if settings.publish_last_seen {
+      sensors.push(LAST_SEEN);
+    }
+    if settings.publish_model {
+      sensors.push(MODEL);
+    }
+    sensors.extend(
+      settings
+        .custom_sensors_of(self.model)
+        .map(|custom| custom.sensor.clone()),
+    );
+
+    sensors.retain(|sensor| settings.publishes_sensor(&self.local_name, sensor.kind));
+    sensors
+  }
+
+  // Decides whether an advertisement should replace the current reading. Readings from the adapter
+  // that provided the current one (self.adapter) are always accepted, readings from other adapters
+  // have to win under multi_adapter_policy unless the current reading is stale
+  pub fn accepts_reading_from(
+    &self,
+    adapter: &str,
+    rssi: Option<i16>,
+    now: i64,
+    settings: &Configuration,
+  ) -> bool {
+    if adapter == self.adapter || now - self.last_seen > ADAPTER_DEDUP_WINDOW_MS {
+      return true;
+    }
 
-      self.last_state_sent = Utc::now().timestamp_millis();
+    if settings.multi_adapter_policy == MultiAdapterPolicy::Priority {
+      let (rank, current_rank) = (
+        settings.adapter_rank(adapter),
+        settings.adapter_rank(&self.adapter),
+      );
+      if rank != current_rank {
+        return rank < current_rank;
+      }
     }
+
+    rssi.unwrap_or(i16::MIN) >= self.rssi.unwrap_or(i16::MIN)
+  }
+
+  // Millisecond epoch time the device was last heard
+  pub fn last_seen(&self) -> i64 {
+    self.last_seen
+  }
+
+  // e.g. "2023-11-14T22:13:20Z", as Home Assistant's timestamp sensors read it
+  fn last_seen_iso(&self) -> Option<String> {
+    Utc
+      .timestamp_millis_opt(self.last_seen)
+      .single()
+      .map(|at| at.to_rfc3339_opts(SecondsFormat::Secs, true))
+  }
+
+  // How long HA waits for a state message before showing the entities unavailable: a few of the
+  // device's state intervals, or of the gaps between its advertisements if it's heard less often
+  // than that, so an occasional missed advertisement doesn't flap the entities
+  fn expire_after_secs(&self, settings: &Configuration) -> u64 {
+    let interval_ms = settings
+      .state_interval_ms()
+      .max(self.advertisement_interval_ms.unwrap_or(0));
+    (interval_ms as f64 * settings.expire_grace_factor / 1000.0).ceil() as u64
+  }
+
+  // Whether the device has gone quiet for longer than HA waits for its readings, so
+  // fixed_cadence_secs stops republishing its last one and lets the entities go unavailable
+  pub fn is_stale(&self, settings: &Configuration, now: i64) -> bool {
+    now - self.last_seen > self.expire_after_secs(settings) as i64 * 1000
+  }
+
+  // Whether this is the ambient_device_id device, the one the others are compared with
+  pub fn is_ambient(&self, settings: &Configuration) -> bool {
+    settings.ambient_device_id.as_deref() == Some(self.device_id.as_str())
+  }
+
+  // Sets the ambient temperature the delta is worked out from, None while there's no fresh ambient
+  // reading so it's left out of the state message. The ambient device has no delta of its own
+  pub fn set_ambient_temperature(&mut self, settings: &Configuration, ambient_c: Option<f32>) {
+    self.ambient_temperature_c = ambient_c.filter(|_| !self.is_ambient(settings));
+  }
+
+  // Notes the device was heard without taking the reading, e.g. for an advertisement too weak to
+  // trust (see min_publish_rssi)
+  pub fn mark_seen(&mut self, now: i64) {
+    self.last_seen = now;
+  }
+
+  // One line per device for the SIGUSR1 dump, e.g. "47:01:01 (T) 24.50°C, battery 88%,
+  // 5E:00:00:00:00:01 via hci0 at -60 dBm, last seen 12s ago: {"temperature_c":24.5,...}"
+  pub fn describe(&self, now: i64, decimal_places: u32) -> String {
+    let signal = match self.rssi {
+      Some(rssi) => format!(" at {} dBm", rssi),
+      None => String::new(),
+    };
+    format!(
+      "{}, {} via {}{}, last seen {}s ago: {}",
+      self,
+      self.address,
+      self.adapter,
+      signal,
+      (now - self.last_seen) / 1000,
+      self.state_reading(decimal_places).to_json().dump()
+    )
+  }
+
+  // The recent readings for the SIGUSR1 dump, oldest first, e.g. "60s ago: {"temperature_c":24.5,...}"
+  pub fn describe_history(&self, now: i64, decimal_places: u32) -> Vec<String> {
+    self
+      .history
+      .iter()
+      .map(|(at, reading)| {
+        let reading = reading.map(|value| round_reading(value, decimal_places));
+        format!("{}s ago: {}", (now - at) / 1000, reading.to_json().dump())
+      })
+      .collect()
   }
 
-  #[allow(unused_mut)] // Client needs to be mutable to send messages for some reason
-  pub fn send_config_messages(&mut self, mut client: AsyncClient) {
-    if self.device_id == "00:00:00" {
-      return ();
+  // Adds the current reading to the history, dropping the oldest beyond history_len
+  pub fn record_history(&mut self, now: i64, history_len: usize) {
+    if history_len == 0 {
+      return;
     }
-    // Home Assistant expects a configuration message be sent for each device:
-    // https://www.home-assistant.io/docs/mqtt/discovery/
-    // The topic must conform to:
-    //   <discovery_prefix>/<component>/[<node_id>/]<object_id>/config
-    //   homeassistant/sensor/47:00:00/config
-    // A JSON payload must be sent with key/values matching config from here:
-    // https://www.home-assistant.io/integrations/sensor.mqtt/
-    // Example payload:
-    /*
-        {
-          name: "Hive 1 Temp Sensor",
-          device: {
-            manufacturer: "Broodminder",
-            identifiers: "47:00:00",
-          },
-          device_class: "temperature",
-          expire_after: 3600,
-          force_update: true,
-          state_class: "measurement",
-          unit_of_measurement: "C"
-          state_topic: "homeassistant/sensor/47:00:00/state",
-          value_template: {{ value_json.temperature_c }}
+    self
+      .history
+      .push_back((now, self.unrounded_state_reading()));
+    while self.history.len() > history_len {
+      self.history.pop_front();
+    }
+  }
+
+  // Warns when the calibrated weight goes outside weight_min_kg..weight_max_kg, once until it comes
+  // back, since published_state_reading runs for every publish, snapshot and REST request
+  pub fn check_weight_range(&mut self, settings: &Configuration) {
+    let (min, max) = (settings.weight_min_kg, settings.weight_max_kg);
+    let out_of_range = self
+      .unrounded_state_reading()
+      .weight_kg
+      .map(|value| value + self.calibration.weight_offset_kg)
+      .filter(|weight_kg| !(min..=max).contains(weight_kg));
+    match out_of_range {
+      Some(weight_kg) if !self.weight_out_of_range => warn!(
+        "{} weighs {:.2} kg, outside weight_min_kg..weight_max_kg ({} to {}), {}",
+        self.device_id,
+        weight_kg,
+        min,
+        max,
+        match settings.weight_out_of_range {
+          WeightOutOfRange::Skip => "leaving it out",
+          WeightOutOfRange::Clamp => "clamping it",
         }
-    */
+      ),
+      None if self.weight_out_of_range => info!(
+        "{} weighs within weight_min_kg..weight_max_kg again",
+        self.device_id
+      ),
+      _ => {}
+    }
+    self.weight_out_of_range = out_of_range.is_some();
+  }
 
-    // Note that sensors that report more than one value will need to send more than one
-    // config message -- one per sensor value type, e.g. humidity and temperature will need
-    // different topics
+  pub fn record_source(&mut self, adapter: String, rssi: Option<i16>, now: i64) {
+    if self.last_seen > 0 {
+      let gap = now - self.last_seen;
+      // Weighted towards the recent gaps, so a change of cadence shows up within a few readings
+      self.advertisement_interval_ms = Some(match self.advertisement_interval_ms {
+        Some(average) => (3 * average + gap) / 4,
+        None => gap,
+      });
+    }
+    if let Some(rssi) = rssi {
+      self.rssi_history.push_back(rssi);
+      if self.rssi_history.len() > SIGNAL_QUALITY_SAMPLES {
+        self.rssi_history.pop_front();
+      }
+    }
+    self.adapter = adapter;
+    self.rssi = rssi;
+    self.last_seen = now;
+  }
 
-    // TODO: Magic numbers should probably be config managed
-    // Only send config every hour
-    if Utc::now().timestamp_millis() - self.last_config_sent > 3600000 {
-      let simple_id = self.device_id.clone().replace(":", "");
+  // The mean of the recent RSSI samples mapped onto 0-100%, from signal_quality_min_dbm (0%) to
+  // signal_quality_max_dbm (100%). None until the device has been heard with an RSSI
+  pub fn signal_quality(&self, settings: &Configuration) -> Option<f64> {
+    if self.rssi_history.is_empty() {
+      return None;
+    }
+    let mean = self
+      .rssi_history
+      .iter()
+      .map(|&rssi| f64::from(rssi))
+      .sum::<f64>()
+      / self.rssi_history.len() as f64;
+    let (min, max) = (
+      f64::from(settings.signal_quality_min_dbm),
+      f64::from(settings.signal_quality_max_dbm),
+    );
+    Some(
+      ((mean - min) / (max - min) * 100.0)
+        .clamp(0.0, 100.0)
+        .round(),
+    )
+  }
 
-      // No more than 1 per hour
-      info!("Publishing configuration via MQTT for {:?}", self.device_id);
+  // Takes the readings from a newer copy of this device, keeping our own publishing state
+  pub fn refresh_from(&mut self, newer: &BroodminderDevice) {
+    let (last_config_sent, last_state_sent) = (self.last_config_sent, self.last_state_sent);
+    let config_published = self.config_published;
+    let last_published = self.last_published.take();
+    let downsampler = std::mem::take(&mut self.downsampler);
+    *self = newer.clone();
+    self.last_config_sent = last_config_sent;
+    self.last_state_sent = last_state_sent;
+    self.config_published = config_published;
+    self.last_published = last_published;
+    self.downsampler = downsampler;
+  }
 
-      // Send temperature configuration message
-      if self.model == 47 || self.model == 57 {
-        let config_message = object! {
-          name: format!("{}_temperature", &self.device_id),
-          device_class: "temperature",
-          expire_after: 3600,
-          force_update: true,
-          state_class: "measurement",
-          unit_of_measurement: "°C",
-          state_topic: format!("homeassistant/sensor/BM{}/state", simple_id),
-          value_template: "{{ value_json.temperature_c }}",
-          unique_id: format!("{}_temperature", simple_id),
-        };
+  // As Home Assistant shows it, e.g. "Broodminder-W", or "Broodminder model 99" for models
+  // brood-flow doesn't know
+  pub fn model_name(&self) -> String {
+    match self.model_info() {
+      Some(info) => format!("Broodminder-{}", info.name),
+      None => format!("Broodminder model {}", self.model),
+    }
+  }
+
+  // As the BroodMinder app shows it, e.g. "3.2"
+  pub fn firmware_version(&self) -> String {
+    format!("{}.{}", self.major_version, self.minor_version)
+  }
+
+  // The readings published in the state message, rounded for publishing
+  pub fn state_reading(&self, decimal_places: u32) -> StateReading {
+    self
+      .unrounded_state_reading()
+      .map(|value| round_reading(value, decimal_places))
+  }
 
-        let config_topic = format!("homeassistant/sensor/BM{}Temp/config", simple_id);
-        info!("Config message: {:?}", config_message.dump());
-        let task_client = client.clone();
-        tokio::task::spawn(async move {
-          match task_client
-            .publish(config_topic, QoS::AtLeastOnce, false, config_message.dump())
-            .await
-          {
-            Err(error) => info!("Error: {:?}", error),
-            Ok(_) => info!("Sent config!"),
+  // The unrounded reading with the aggregated temperature, which is only sent with an entity to
+  // read it and in that entity's unit
+  fn published_state_reading(&self, settings: &Configuration) -> StateReading {
+    let mut reading = self.unrounded_state_reading();
+    match settings.aggregated_temperature_unit {
+      Some(TemperatureUnit::Celsius) => {
+        reading.aggregated_temperature_c = Some(self.temperature_c as f64)
+      }
+      Some(TemperatureUnit::Fahrenheit) => {
+        reading.aggregated_temperature_f = Some(self.temperature_f as f64)
+      }
+      None => {}
+    }
+    let calibration = &self.calibration;
+    for temperature_c in [
+      &mut reading.temperature_c,
+      &mut reading.aggregated_temperature_c,
+      &mut reading.temp_delta_c,
+    ] {
+      *temperature_c = temperature_c.map(|value| value + calibration.temperature_offset_c);
+    }
+    for temperature_f in [
+      &mut reading.temperature_f,
+      &mut reading.aggregated_temperature_f,
+    ] {
+      *temperature_f = temperature_f.map(|value| value + calibration.temperature_offset_c * 1.8);
+    }
+    reading.weight_kg = reading
+      .weight_kg
+      .map(|value| value + calibration.weight_offset_kg);
+    reading.weight_lbs = reading
+      .weight_lbs
+      .map(|value| value + calibration.weight_offset_kg * f64::from(LBS_PER_KG));
+    // Fresh batteries can read a few percent over 100. The manual gives the byte as a plain
+    // percentage, so nothing above 100 means anything more
+    if settings.clamp_battery && self.battery_percent > 100 {
+      debug!(
+        "Clamping the battery of {} from {}% to 100%",
+        self.device_id, self.battery_percent
+      );
+      reading.battery_percent = Some(100.0);
+    }
+    if let Some(weight_kg) = reading.weight_kg {
+      let (min, max) = (settings.weight_min_kg, settings.weight_max_kg);
+      if !(min..=max).contains(&weight_kg) {
+        let weight_kg = match settings.weight_out_of_range {
+          WeightOutOfRange::Skip => None,
+          WeightOutOfRange::Clamp => Some(weight_kg.clamp(min, max)),
+        };
+        // check_weight_range warned when the weight went out of range
+        debug!(
+          "{} weighs {:.2} kg, outside weight_min_kg..weight_max_kg ({} to {}), {}",
+          self.device_id,
+          reading.weight_kg.unwrap_or_default(),
+          min,
+          max,
+          match weight_kg {
+            Some(_) => "clamping it",
+            None => "leaving it out",
           }
-        });
+        );
+        reading.weight_kg = weight_kg;
+        reading.weight_lbs = weight_kg.map(|weight_kg| weight_kg * f64::from(LBS_PER_KG));
+      }
+    }
+    if settings.publish_signal_quality {
+      reading.signal_quality_percent = self.signal_quality(settings);
+    }
+    if settings.publish_resets {
+      reading.resets = Some(self.resets as f64);
+    }
+    if settings.publish_last_seen {
+      reading.last_seen = self.last_seen_iso();
+    }
+    for custom in settings.custom_sensors_of(self.model) {
+      if let Some(value) = custom.definition.decode(&self.payload) {
+        reading.custom.insert(custom.sensor.state_key, value);
       }
+    }
+    reading
+  }
 
-      // Send weight configuration message
-      if self.model == 57 {
-        let config_message = object! {
-          name: format!("{}_weight", &self.device_id),
-          expire_after: 3600,
-          force_update: true,
-          state_class: "measurement",
-          unit_of_measurement: "kg",
-          state_topic: format!("homeassistant/sensor/BM{}/state", simple_id),
-          value_template: "{{ value_json.weight_lbs }}",
-          unique_id: format!("{}_weight", simple_id),
-        };
+  fn unrounded_state_reading(&self) -> StateReading {
+    StateReading {
+      temperature_c: Some(self.realtime_temperature_c as f64),
+      temperature_f: Some(self.realtime_temperature_f as f64),
+      // Filled in by published_state_reading
+      aggregated_temperature_c: None,
+      aggregated_temperature_f: None,
+      battery_percent: Some(self.battery_percent as f64),
+      temperature_probe1_c: self.temperature_probe1_c.map(f64::from),
+      temperature_probe2_c: self.temperature_probe2_c.map(f64::from),
+      temp_delta_c: self
+        .ambient_temperature_c
+        .map(|ambient_c| f64::from(self.realtime_temperature_c - ambient_c)),
+      weight_kg: self.realtime_weight_kg.map(f64::from),
+      weight_lbs: self.realtime_weight_lbs.map(f64::from),
+      pressure_hpa: self.pressure_hpa.map(f64::from),
+      activity: self.activity.map(f64::from),
+      rssi: self.rssi.map(f64::from),
+      // Filled in by published_state_reading
+      signal_quality_percent: None,
+      resets: None,
+      // Filled in by published_state_reading
+      last_seen: None,
+      custom: BTreeMap::new(),
+    }
+  }
 
-        let config_topic = format!("homeassistant/sensor/BM{}Weight/config", simple_id);
-        info!("Config message: {:?}", config_message.dump());
-        let task_client = client.clone();
-        tokio::task::spawn(async move {
-          match task_client
-            .publish(config_topic, QoS::AtLeastOnce, false, config_message.dump())
-            .await
-          {
-            Err(error) => info!("Error: {:?}", error),
-            Ok(_) => info!("Sent config!"),
-          }
-        });
+  // The reading to publish now, if any. Normally the latest reading, no more than 1 per 30s. With
+  // downsample_secs the mean of each window is published as the window ends instead.
+  // Call once per reading, as downsampling counts the calls. Every output keeps its own copy of
+  // the device, so each one is rate limited independently
+  pub fn next_state_reading(&mut self, settings: &Configuration, now: i64) -> Option<StateReading> {
+    let reading = self.due_state_reading(settings, now)?;
+    self.last_state_sent = now;
+
+    // Unchanged readings are held back, but still sent every max_unchanged_secs so HA's
+    // expire_after doesn't mark the entities unavailable
+    if settings.publish_on_change_only {
+      if let Some((previous, published_at)) = &self.last_published {
+        let max_unchanged_ms = settings.max_unchanged_secs as i64 * 1000;
+        let changed = reading.changed_from(previous, |key| settings.min_change(key));
+        if !changed && now - published_at < max_unchanged_ms {
+          debug!("Skipping unchanged state for {}", self.device_id);
+          return None;
+        }
       }
+      self.last_published = Some((reading.clone(), now));
+    }
+    Some(reading)
+  }
 
-      self.last_config_sent = Utc::now().timestamp_millis();
+  // The device's entry in a snapshot (see snapshot_topic): who it is and its current reading, as
+  // the state message would have it
+  #[cfg(any(feature = "mqtt", feature = "rest"))]
+  pub fn snapshot(&self, settings: &Configuration) -> JsonValue {
+    let mut reading = self
+      .published_state_reading(settings)
+      .map(|value| round_reading(value, settings.decimal_places));
+    reading.retain_kinds(|kind| settings.publishes_sensor(&self.local_name, kind));
+    object! {
+      device_id: self.device_id.clone(),
+      address: self.address.clone(),
+      model: self.model_name(),
+      last_seen: self.last_seen_iso(),
+      state: reading.to_json(),
     }
   }
+
+  // A newly seen device (nothing sent yet) is published right away with publish_on_first_seen,
+  // so it shows up in HA promptly, otherwise it waits out a first interval like any other
+  fn due_state_reading(&mut self, settings: &Configuration, now: i64) -> Option<StateReading> {
+    let first_seen = self.last_state_sent == 0;
+
+    // The cadence timer decides when, this is only called on its ticks
+    if settings.fixed_cadence_secs.is_some() {
+      return Some(
+        self
+          .published_state_reading(settings)
+          .map(|value| round_reading(value, settings.decimal_places)),
+      );
+    }
+
+    match settings.downsample_secs {
+      Some(secs) => {
+        // The first reading still starts the first window
+        let mean = self.downsampler.add(
+          self.published_state_reading(settings),
+          now,
+          secs as i64 * 1000,
+        );
+        if first_seen && settings.publish_on_first_seen {
+          return Some(
+            self
+              .published_state_reading(settings)
+              .map(|value| round_reading(value, settings.decimal_places)),
+          );
+        }
+        mean.map(|mean| {
+          let mut mean = mean.map(|value| round_reading(value, settings.decimal_places));
+          // A count isn't averaged, the window ends with however many there have been. Neither is
+          // the time, which is when the window's last reading was heard
+          if mean.resets.is_some() {
+            mean.resets = Some(self.resets as f64);
+          }
+          if mean.last_seen.is_some() {
+            mean.last_seen = self.last_seen_iso();
+          }
+          mean
+        })
+      }
+      None if first_seen && !settings.publish_on_first_seen => {
+        // Start the rate limit interval as if the first reading had been sent
+        self.last_state_sent = now;
+        None
+      }
+      // TODO: Magic numbers should be managed by config
+      None if now - self.last_state_sent > 30000 => Some(
+        self
+          .published_state_reading(settings)
+          .map(|value| round_reading(value, settings.decimal_places)),
+      ),
+      None => None,
+    }
+  }
+}
+
+// Publishing to MQTT (Home Assistant discovery and state messages)
+#[cfg(feature = "mqtt")]
+impl BroodminderDevice {
+  // Removes entities from HomeAssistant, e.g. the ones a device was published under before its
+  // topics changed (see topic_history)
+  pub fn send_delete_messages(
+    &self,
+    publisher: &Publisher,
+    settings: &Configuration,
+    config_topics: &[String],
+  ) {
+    // Home Assistant will delete any device it receives an empty config message for
+    // The topic must conform to:
+    //   <discovery_prefix>/<component>/[<node_id>/]<object_id>/config
+    //   homeassistant/sensor/47:00:00/config
+    // A JSON payload must be empty. It's retained so it also clears a retained config message
+    let (qos, _) = settings.publish_options(&self.local_name);
+    for config_topic in config_topics {
+      info!("Deleting {} for {}", config_topic, self.device_id);
+      publisher.publish(config_topic.clone(), qos, true, "", "config");
+    }
+  }
+
+  // Where each of this device's entities is published, to notice when that changes between runs
+  pub fn discovery_entries(&self, settings: &Configuration) -> Vec<DiscoveryEntry> {
+    self
+      .sensors(settings)
+      .iter()
+      .map(|sensor| DiscoveryEntry {
+        sensor_id: sensor.id.to_string(),
+        config_topic: self.config_topic(settings, sensor),
+        unique_id: self.unique_id(sensor),
+      })
+      .collect()
+  }
+
+  fn unique_id(&self, sensor: &Sensor) -> String {
+    format!("{}_{}", topics::canonical_id(&self.device_id), sensor.id)
+  }
+
+  // Sends a HomeAssistant compatible MQTT message with an update on the state of the device
+  // (e.g. the current temperature, humidity, weight, or other data as appropriate).
+  // Call once per reading (see next_state_reading). `now` is the millisecond epoch time
+  pub fn send_state_message(&mut self, publisher: &Publisher, settings: &Configuration, now: i64) {
+    if self.device_id == UNKNOWN_DEVICE_ID {
+      return;
+    }
+    // HA drops state for entities it doesn't know yet, e.g. while startup_delay_secs holds back
+    // the config
+    if settings.publish_discovery && settings.require_config_before_state && !self.config_published
+    {
+      debug!(
+        "Holding state for {} until its config is sent",
+        self.device_id
+      );
+      return;
+    }
+
+    // State topic should be whatever is set in 'state_topic' in the config message
+    // e.g.
+    // homeassistant/sensor/47:00:00/state
+    // And should contain a json object that can be parsed by the 'value_template'
+    // See: https://www.home-assistant.io/docs/configuration/templating/#processing-incoming-data
+    if let Some(mut reading) = self.next_state_reading(settings, now) {
+      reading.retain_kinds(|kind| settings.publishes_sensor(&self.local_name, kind));
+      info!("Publishing state via MQTT for {:?}", self.device_id);
+
+      let state_topic = self.state_topic(settings);
+      info!(
+        "Publishing: {} to {}",
+        reading.to_json().dump(),
+        state_topic
+      );
+
+      let (qos, retain) = settings.publish_options(&self.local_name);
+      if settings.topic_per_value {
+        // Bare values, for consumers that want one topic per reading (e.g. Node-RED)
+        for (key, value) in reading.readings() {
+          let topic = self.value_topic(settings, key);
+          publisher.publish(topic, qos, retain, JsonValue::from(value).dump(), "state");
+        }
+        if let Some(last_seen) = &reading.last_seen {
+          let topic = self.value_topic(settings, LAST_SEEN.state_key);
+          publisher.publish(topic, qos, retain, last_seen.clone(), "state");
+        }
+      } else {
+        let payload = match (&settings.state_payload_template, settings.payload_encoding) {
+          (Some(template), _) => payload_template::render(template, &reading, &self.device_id, now)
+            .dump()
+            .into_bytes(),
+          (None, PayloadEncoding::Json) if settings.single_state_message => {
+            self.combined_state(settings, &reading).dump().into_bytes()
+          }
+          (None, PayloadEncoding::Json) => reading.to_json().dump().into_bytes(),
+          (None, PayloadEncoding::Msgpack) => match rmp_serde::to_vec_named(&reading) {
+            Ok(payload) => payload,
+            Err(error) => {
+              warn!(
+                "Couldn't encode the state of {} as MessagePack, skipping it: {}",
+                self.device_id, error
+              );
+              return;
+            }
+          },
+        };
+        publisher.publish(state_topic, qos, retain, payload, "state");
+      }
+
+      if settings.single_state_message {
+        return;
+      }
+      if let Some(attributes) = self.attributes(settings) {
+        let (attributes_topic, payload) = if settings.compress_attributes {
+          // MQTT 3.1.1 has no content encoding property, so the topic says how to decode it
+          (
+            format!("{}/gzip", self.attributes_topic(settings)),
+            gzip(attributes.dump().as_bytes()),
+          )
+        } else {
+          (
+            self.attributes_topic(settings),
+            attributes.dump().into_bytes(),
+          )
+        };
+        publisher.publish(attributes_topic, qos, retain, payload, "attributes");
+      }
+    }
+  }
+
+  // The prefix of the entity ids HA creates, e.g. "bm_5e0000000001" for sensor.bm_5e0000000001_weight.
+  // Taken from the MAC address (or the device's object_id setting) rather than the name, so
+  // renaming a device doesn't rename its entities and break automations
+  fn object_id(&self, settings: &Configuration) -> String {
+    if let Some(object_id) = settings
+      .device(&self.local_name)
+      .and_then(|device| device.object_id.clone())
+    {
+      return object_id;
+    }
+    let id = if self.address.is_empty() {
+      &self.device_id
+    } else {
+      &self.address
+    };
+    format!("bm_{}", topics::canonical_id(id).to_lowercase())
+  }
+
+  // Forgets when config was last sent, so the next send_config_messages goes out straight away
+  pub fn request_config(&mut self) {
+    self.last_config_sent = 0;
+  }
+
+  // Has the next send_config_messages from `delay_ms` after `now` on resend the config, rather
+  // than waiting out the rest of the hour
+  pub fn request_config_after(&mut self, now: i64, delay_ms: i64) {
+    self.last_config_sent = self
+      .last_config_sent
+      .min(now + delay_ms - CONFIG_INTERVAL_MS);
+  }
+
+  pub fn send_config_messages(
+    &mut self,
+    publisher: &Publisher,
+    settings: &Configuration,
+    now: i64,
+  ) {
+    if self.device_id == UNKNOWN_DEVICE_ID {
+      return;
+    }
+    // Home Assistant expects a configuration message be sent for each device:
+    // https://www.home-assistant.io/docs/mqtt/discovery/
+    // The topic must conform to:
+    //   <discovery_prefix>/<component>/[<node_id>/]<object_id>/config
+    //   homeassistant/sensor/47:00:00/config
+    // A JSON payload must be sent with key/values matching config from here:
+    // https://www.home-assistant.io/integrations/sensor.mqtt/
+    // Example payload:
+    /*
+        {
+          name: "Hive 1 Temp Sensor",
+          device: {
+            manufacturer: "Broodminder",
+            identifiers: "47:00:00",
+          },
+          device_class: "temperature",
+          expire_after: 90,
+          force_update: true,
+          state_class: "measurement",
+          unit_of_measurement: "C"
+          state_topic: "homeassistant/sensor/47:00:00/state",
+          value_template: {{ value_json.temperature_c }}
+        }
+    */
+
+    // Note that sensors that report more than one value will need to send more than one
+    // config message -- one per sensor value type, e.g. humidity and temperature will need
+    // different topics
+
+    // TODO: Magic numbers should probably be config managed
+    // Only send config every hour
+    if now - self.last_config_sent > CONFIG_INTERVAL_MS {
+      // No more than 1 per hour
+      info!("Publishing configuration via MQTT for {:?}", self.device_id);
+
+      for sensor in self.sensors(settings) {
+        let state_topic = if settings.topic_per_value {
+          self.value_topic(settings, sensor.state_key)
+        } else {
+          self.state_topic(settings)
+        };
+        let mut config_message = object! {
+          name: format!("{}_{}", &self.device_id, sensor.id),
+          expire_after: self.expire_after_secs(settings),
+          force_update: true,
+          state_topic: state_topic,
+          unique_id: self.unique_id(&sensor),
+          object_id: format!("{}_{}", self.object_id(settings), sensor.id),
+        };
+        // The last seen time stays up once the device goes quiet, that's what it's there to show.
+        // The model is only published with the config, so it can't expire either
+        if sensor == LAST_SEEN || sensor == MODEL {
+          config_message.remove("expire_after");
+        }
+        // The model has a retained topic of its own rather than a key in every state message
+        if sensor == MODEL {
+          config_message["state_topic"] = self.value_topic(settings, MODEL.state_key).into();
+        }
+        match sensor.component {
+          Component::Sensor => {
+            // Home Assistant doesn't take a state class for timestamps or text
+            if sensor.device_class != Some("timestamp") && sensor != MODEL {
+              config_message["state_class"] = "measurement".into();
+            }
+            if !sensor.unit.is_empty() {
+              config_message["unit_of_measurement"] = sensor.unit.into();
+            }
+            // A topic per value carries just the value, which HA reads as it is
+            if !settings.topic_per_value && sensor != MODEL {
+              config_message["value_template"] =
+                format!("{{{{ value_json.{} }}}}", sensor.state_key).into();
+            }
+            if let Some(precision) = settings.display_precision(&self.local_name) {
+              config_message["suggested_display_precision"] = precision.into();
+            }
+          }
+          // Binary sensors are derived from a reading in the state message
+          Component::BinarySensor { on_when } => {
+            config_message["payload_on"] = "ON".into();
+            config_message["payload_off"] = "OFF".into();
+            let value = if settings.topic_per_value {
+              "value | float".to_string()
+            } else {
+              format!("value_json.{}", sensor.state_key)
+            };
+            config_message["value_template"] = format!(
+              "{{{{ 'ON' if {} {} else 'OFF' }}}}",
+              value,
+              on_when.comparison()
+            )
+            .into();
+          }
+        }
+        if let Some(device_class) = sensor.device_class {
+          config_message["device_class"] = device_class.into();
+        }
+        if sensor.diagnostic {
+          config_message["enabled_by_default"] =
+            settings.diagnostics_enabled(&self.local_name).into();
+          if settings.categorize_diagnostics {
+            config_message["entity_category"] = "diagnostic".into();
+          }
+        }
+        if sensor.unconfirmed && !settings.enable_unconfirmed_sensors {
+          config_message["enabled_by_default"] = false.into();
+        }
+
+        let config_topic = self.config_topic(settings, &sensor);
+        self.publish_config_message(publisher, settings, config_topic, config_message);
+        if sensor == MODEL {
+          let (qos, _) = settings.publish_options(&self.local_name);
+          let topic = self.value_topic(settings, MODEL.state_key);
+          publisher.publish(topic, qos, true, self.model_name(), "state");
+        }
+      }
+
+      self.last_config_sent = now;
+      self.config_published = true;
+    }
+  }
+
+  fn topic_values<'a>(
+    settings: &'a Configuration,
+    component: &'a str,
+    simple_id: &'a str,
+    sensor: &'a str,
+  ) -> TopicValues<'a> {
+    TopicValues {
+      prefix: &settings.discovery_prefix,
+      component,
+      device_id: simple_id,
+      sensor,
+    }
+  }
+
+  // The {device_id} of the device's topics, in its canonical form. Devices heard without a MAC
+  // address (e.g. from captures) fall back to the local name
+  fn topic_id(&self, settings: &Configuration) -> String {
+    let id = match settings.topic_id_source {
+      TopicIdSource::Mac if !self.address.is_empty() => &self.address,
+      _ => &self.device_id,
+    };
+    topics::canonical_id(id)
+  }
+
+  pub fn state_topic(&self, settings: &Configuration) -> String {
+    let simple_id = self.topic_id(settings);
+    topics::render(
+      &settings.state_topic_template,
+      &Self::topic_values(settings, "sensor", &simple_id, ""),
+    )
+  }
+
+  // With topic_per_value, where one reading is published, e.g. ".../BM470101/state/temperature_c"
+  fn value_topic(&self, settings: &Configuration, state_key: &str) -> String {
+    format!("{}/{}", self.state_topic(settings), state_key)
+  }
+
+  pub fn attributes_topic(&self, settings: &Configuration) -> String {
+    let simple_id = self.topic_id(settings);
+    topics::render(
+      &settings.attributes_topic_template,
+      &Self::topic_values(settings, "sensor", &simple_id, ""),
+    )
+  }
+
+  // The discovery topic for one sensor of the device
+  pub fn config_topic(&self, settings: &Configuration, sensor: &Sensor) -> String {
+    let simple_id = self.topic_id(settings);
+    topics::render(
+      &settings.config_topic_template,
+      &Self::topic_values(
+        settings,
+        sensor.component.as_str(),
+        &simple_id,
+        sensor.topic,
+      ),
+    )
+  }
+
+  // The `device` block shared by every sensor of this device, so HA groups them under one device.
+  // It's named after the device's configured name, if it has one
+  fn device_block(&self, settings: &Configuration) -> JsonValue {
+    let name = settings
+      .device(&self.local_name)
+      .and_then(|device| device.name.clone())
+      .unwrap_or_else(|| self.device_id.clone());
+    let mut device = object! {
+      identifiers: [self.device_id.clone()],
+      manufacturer: "Broodminder",
+      name: name,
+    };
+
+    if self.model_info().is_some() {
+      device["model"] = self.model_name().into();
+    }
+
+    if settings.publish_firmware {
+      device["sw_version"] = self.firmware_version().into();
+    }
+
+    if let Some(gateway_id) = &settings.gateway_id {
+      device["via_device"] = gateway_id.clone().into();
+    }
+
+    device
+  }
+
+  // Extra attributes published to the attributes topic, None if there's nothing to publish
+  fn attributes(&self, settings: &Configuration) -> Option<JsonValue> {
+    let mut attributes = JsonValue::new_object();
+
+    if settings.publish_raw {
+      attributes["raw_hex"] = self.raw_hex.clone().into();
+    }
+
+    // e.g. {"653": 25, "76": 4}, an Apple iBeacon alongside the Broodminder payload
+    if settings.publish_manufacturer_data {
+      let mut lengths = JsonValue::new_object();
+      for (id, length) in &self.manufacturer_lengths {
+        lengths[id.to_string().as_str()] = (*length).into();
+      }
+      attributes["manufacturer_data"] = lengths;
+    }
+
+    if settings.publish_firmware {
+      attributes["firmware"] = self.firmware_version().into();
+    }
+
+    if attributes.is_empty() {
+      None
+    } else {
+      Some(attributes)
+    }
+  }
+
+  // The state message with single_state_message: every reading, plus the attributes under
+  // "attributes", so the device needs one publish per reading
+  fn combined_state(&self, settings: &Configuration, reading: &StateReading) -> JsonValue {
+    let mut state = reading.to_json();
+    if let Some(attributes) = self.attributes(settings) {
+      state["attributes"] = attributes;
+    }
+    state
+  }
+
+  // Publishes a single discovery config message, adding the keys every sensor of the device shares
+  fn publish_config_message(
+    &self,
+    publisher: &Publisher,
+    settings: &Configuration,
+    config_topic: String,
+    mut config_message: JsonValue,
+  ) {
+    config_message["device"] = self.device_block(settings);
+
+    // Attributes show up on every entity of the device in HA, as long as HA can decode them
+    if self.attributes(settings).is_some() && settings.single_state_message {
+      config_message["json_attributes_topic"] = self.state_topic(settings).into();
+      config_message["json_attributes_template"] = "{{ value_json.attributes | tojson }}".into();
+    } else if self.attributes(settings).is_some() && !settings.compress_attributes {
+      config_message["json_attributes_topic"] = self.attributes_topic(settings).into();
+    }
+
+    // An empty encoding tells HA to hand the raw payload bytes to the value_template instead of
+    // decoding it as utf-8. HA's templates have no msgpack filter, so msgpack state is meant for
+    // consumers that decode it themselves (or an HA integration that does)
+    if settings.payload_encoding == PayloadEncoding::Msgpack {
+      config_message["encoding"] = "".into();
+    }
+
+    // Entities go unavailable in HA when brood-flow loses its connection
+    config_message["availability_topic"] = settings.availability_topic.clone().into();
+
+    info!("Config message: {:?}", config_message.dump());
+    let (qos, retain) = settings.publish_options(&self.local_name);
+    publisher.publish(config_topic, qos, retain, config_message.dump(), "config");
+  }
+}
+
+// Temperatures are two bytes, pulled from the Broodminder manual. Every model in MODELS uses the
+// same encoding, hundredths of a degree offset by 50°C, so it covers -50°C up. The bytes are widened
+// to f32 before the arithmetic, so nothing wraps going below zero
+fn decode_temperature_c(low: u8, high: u8) -> f32 {
+  (256.0 * high as f32 + low as f32 - 5000.0) / 100.0
+}
+
+// Scale weights are a little endian u16 in hundredths of a kg, offset by 32767 so a scale reading
+// slightly below its tare comes out negative rather than wrapping, per the Broodminder scale manual
+fn decode_weight_kg(low: u8, high: u8) -> f32 {
+  let raw = i32::from(u16::from_le_bytes([low, high]));
+  (raw - 32767) as f32 / 100.0
+}
+
+// Whether the elapsed counter going from previous to current means the sensor restarted, rather
+// than an advertisement arriving out of order or the counter wrapping
+fn is_reset(previous: u16, current: u16) -> bool {
+  current.saturating_add(ELAPSED_REORDER_TICKS) < previous
+    && previous < u16::MAX - ELAPSED_WRAP_TICKS
+}
+
+// The parser reads bytes up to MIN_PAYLOAD_LEN without checking each one
+fn check_payload_len(data: &[u8]) -> Result<(), Error> {
+  if data.len() < MIN_PAYLOAD_LEN {
+    return Err(Error::Parse(format!(
+      "a {} byte advertisement is too short to decode, it needs {}",
+      data.len(),
+      MIN_PAYLOAD_LEN
+    )));
+  }
+  Ok(())
+}
+
+// Rounds a reading to the given number of decimal places for publishing. The json crate widens f32
+// to f64 before serializing, so a rounded f32 would still come out as e.g. 21.329999923706055; the
+// readings are widened first and rounded in f64 so the published value is the shortest decimal
+// representation
+fn round_reading(value: f64, decimal_places: u32) -> f64 {
+  let factor = 10f64.powi(decimal_places as i32);
+  (value * factor).round() / factor
+}
+
+// Gzips a payload for compress_attributes. The encoder's only errors are its writer's, and
+// writing to a Vec can't fail, so neither can this
+#[cfg(feature = "mqtt")]
+fn gzip(payload: &[u8]) -> Vec<u8> {
+  let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+  encoder
+    .write_all(payload)
+    .expect("gzipping into a Vec can't fail");
+  encoder.finish().expect("gzipping into a Vec can't fail")
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  // 2023-11-14T22:13:20Z, when the publishing tests run
+  #[cfg(feature = "mqtt")]
+  const TEST_NOW: i64 = 1_700_000_000_000;
+
+  // A model 47 (T) advertisement, firmware 3.2, 26.5°C
+  const MODEL_47_PAYLOAD: [u8; 25] = [
+    47, 2, 3, 0xE2, 88, 0x14, 0x00, 0xE0, 0x1D, 0x1D, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0xFF, 0x7F, 0, 0,
+    0, 0,
+  ];
+
+  #[test]
+  fn is_broodminder_with_broodminder_key() {
+    let data = HashMap::from([(653, MODEL_47_PAYLOAD.to_vec())]);
+    assert!(BroodminderDevice::is_broodminder(&data, None));
+  }
+
+  #[test]
+  fn is_broodminder_with_other_keys_only() {
+    let data = HashMap::from([(76, vec![2, 21, 0, 1]), (117, vec![66, 4, 1, 128])]);
+    assert!(!BroodminderDevice::is_broodminder(&data, None));
+  }
+
+  #[test]
+  fn is_broodminder_with_empty_map() {
+    let data = HashMap::new();
+    assert!(!BroodminderDevice::is_broodminder(&data, None));
+  }
+
+  #[test]
+  fn is_broodminder_with_broodminder_and_other_keys() {
+    let data = HashMap::from([
+      (76, vec![2, 21, 0, 1]),
+      (653, MODEL_47_PAYLOAD.to_vec()),
+      (117, vec![66, 4, 1, 128]),
+    ]);
+    assert!(BroodminderDevice::is_broodminder(&data, None));
+  }
+
+  #[test]
+  fn is_broodminder_checks_model_when_models_given() {
+    let data = HashMap::from([(653, MODEL_47_PAYLOAD.to_vec())]);
+    assert!(BroodminderDevice::is_broodminder(
+      &data,
+      Some(&known_models())
+    ));
+    assert!(!BroodminderDevice::is_broodminder(&data, Some(&[57])));
+
+    let mut unknown = MODEL_47_PAYLOAD.to_vec();
+    unknown[0] = 200;
+    let data = HashMap::from([(653, unknown)]);
+    assert!(!BroodminderDevice::is_broodminder(
+      &data,
+      Some(&known_models())
+    ));
+    assert!(BroodminderDevice::is_broodminder(&data, None));
+  }
+
+  #[test]
+  fn accepts_strongest_reading_across_adapters() {
+    let settings = crate::brood_flow_config::parse("devices: []").unwrap();
+    let mut device = BroodminderDevice::build_broodminder_device(&MODEL_47_PAYLOAD).unwrap();
+    device.record_source("hci0".to_string(), Some(-60), 1000);
+
+    // Same adapter always wins, regardless of signal
+    assert!(device.accepts_reading_from("hci0", Some(-90), 2000, &settings));
+    // Another adapter needs a signal at least as strong
+    assert!(!device.accepts_reading_from("hci1", Some(-75), 2000, &settings));
+    assert!(device.accepts_reading_from("hci1", Some(-55), 2000, &settings));
+    assert!(!device.accepts_reading_from("hci1", None, 2000, &settings));
+    // Unless the current reading has gone stale
+    assert!(device.accepts_reading_from(
+      "hci1",
+      Some(-75),
+      1000 + ADAPTER_DEDUP_WINDOW_MS + 1,
+      &settings
+    ));
+  }
+
+  #[test]
+  fn priority_policy_prefers_listed_adapters() {
+    let settings = crate::brood_flow_config::parse(
+      "multi_adapter_policy: \"priority\"\nadapter_priority: [\"hci1\", \"hci0\"]\ndevices: []",
+    )
+    .unwrap();
+    let mut device = BroodminderDevice::build_broodminder_device(&MODEL_47_PAYLOAD).unwrap();
+    device.record_source("hci0".to_string(), Some(-60), 1000);
+
+    // A preferred adapter wins even with a weaker signal, an unlisted one never does
+    assert!(device.accepts_reading_from("hci1", Some(-90), 2000, &settings));
+    assert!(!device.accepts_reading_from("hci2", Some(-40), 2000, &settings));
+  }
+
+  #[test]
+  fn round_reading_serializes_cleanly() {
+    assert_eq!(json::from(round_reading(21.33, 2)).dump(), "21.33");
+    assert_eq!(json::from(round_reading(21.3, 2)).dump(), "21.3");
+    assert_eq!(json::from(round_reading(-3.46, 1)).dump(), "-3.5");
+    assert_eq!(json::from(round_reading(24.0, 2)).dump(), "24");
+    assert_eq!(json::from(round_reading(24.56, 0)).dump(), "25");
+  }
+
+  #[test]
+  fn sub_zero_temperatures_decode() {
+    // (raw bytes low, high), °C, °F
+    let cases = [
+      ((0x88, 0x13), 0.0, 32.0),    // 5000
+      ((0x6E, 0x0F), -10.5, 13.1),  // 3950
+      ((0xE8, 0x03), -40.0, -40.0), // 1000
+      ((0x87, 0x13), -0.01, 31.98), // 4999
+      ((0x00, 0x00), -50.0, -58.0), // The lowest the encoding goes
+    ];
+    for ((low, high), celsius, fahrenheit) in cases {
+      for model in known_models() {
+        let mut payload = MODEL_47_PAYLOAD;
+        payload[0] = model;
+        payload[3] = low;
+        payload[9] = high;
+        payload[7] = low;
+        payload[8] = high;
+        let device = BroodminderDevice::build_broodminder_device(&payload).unwrap();
+        let reading = device.state_reading(2);
+        assert_eq!(reading.temperature_c, Some(celsius), "model {}", model);
+        assert_eq!(reading.temperature_f, Some(fahrenheit), "model {}", model);
+        assert_eq!(round_reading(device.temperature_c as f64, 2), celsius);
+        assert_eq!(round_reading(device.temperature_f as f64, 2), fahrenheit);
+      }
+    }
+  }
+
+  #[test]
+  fn state_reading_encodes_as_msgpack_map() {
+    let device = BroodminderDevice::build_broodminder_device(&MODEL_47_PAYLOAD).unwrap();
+    let reading = device.state_reading(2);
+    let decoded: HashMap<String, f64> =
+      rmp_serde::from_slice(&rmp_serde::to_vec_named(&reading).unwrap()).unwrap();
+
+    assert_eq!(decoded.len(), 3);
+    assert_eq!(Some(decoded["temperature_c"]), reading.temperature_c);
+    assert_eq!(Some(decoded["temperature_f"]), reading.temperature_f);
+  }
+
+  #[test]
+  fn weather_model_decodes_pressure() {
+    let mut payload = MODEL_47_PAYLOAD;
+    payload[0] = WEATHER_MODEL;
+    // 10132 tenths of a hPa
+    payload[15] = 0x94;
+    payload[16] = 0x27;
+    let device = BroodminderDevice::build_broodminder_device(&payload).unwrap();
+    assert_eq!(device.pressure_hpa, Some(1013.2));
+    assert_eq!(device.state_reading(1).pressure_hpa, Some(1013.2));
+
+    // Other models leave the bytes alone
+    let device = BroodminderDevice::build_broodminder_device(&MODEL_47_PAYLOAD).unwrap();
+    assert_eq!(device.state_reading(1).pressure_hpa, None);
+  }
+
+  #[test]
+  fn update_stores_raw_hex() {
+    let device = BroodminderDevice::build_broodminder_device(&MODEL_47_PAYLOAD).unwrap();
+    assert_eq!(
+      device.raw_hex,
+      "2f0203e2581400e01d1d000000000000000000ff7f00000000"
+    );
+  }
+
+  #[test]
+  fn display_is_a_short_summary() {
+    let mut device = BroodminderDevice::build_broodminder_device(&MODEL_47_PAYLOAD).unwrap();
+    device.device_id = "47:01:01".to_string();
+    assert_eq!(device.to_string(), "47:01:01 (T) 26.50°C, battery 88%");
+
+    let mut payload = MODEL_47_PAYLOAD;
+    payload[0] = 99;
+    let mut device = BroodminderDevice::build_broodminder_device(&payload).unwrap();
+    device.device_id = "47:01:01".to_string();
+    assert!(device.to_string().starts_with("47:01:01 (model 99) "));
+  }
+
+  #[test]
+  fn describe_has_the_source_and_readings() {
+    let mut device = BroodminderDevice::build_broodminder_device(&MODEL_47_PAYLOAD).unwrap();
+    device.device_id = "47:01:01".to_string();
+    device.address = "5E:00:00:00:00:01".to_string();
+    device.record_source("hci0".to_string(), Some(-60), 1_700_000_000_000);
+    assert_eq!(
+      device.describe(1_700_000_012_500, 1),
+      "47:01:01 (T) 26.50°C, battery 88%, 5E:00:00:00:00:01 via hci0 at -60 dBm, last seen 12s \
+       ago: {\"temperature_c\":26.5,\"temperature_f\":79.7,\"battery_percent\":88,\"rssi\":-60}"
+    );
+  }
+
+  #[test]
+  fn th_is_decoded_like_a_t() {
+    let settings = crate::brood_flow_config::parse("devices: []").unwrap();
+    let t = BroodminderDevice::build_broodminder_device(&MODEL_47_PAYLOAD).unwrap();
+    let mut payload = MODEL_47_PAYLOAD;
+    payload[0] = 56;
+    let th = BroodminderDevice::build_broodminder_device(&payload).unwrap();
+    assert_eq!(th.model_name(), "Broodminder-TH");
+    assert_eq!(th.sensors(&settings), t.sensors(&settings));
+    assert_eq!(
+      th.state_reading(2).readings(),
+      t.state_reading(2).readings()
+    );
+  }
+
+  #[test]
+  fn second_probe_is_decoded_for_dual_probe_models() {
+    let info = ModelInfo {
+      model: 47,
+      name: "T",
+      weight: false,
+      pressure: false,
+      second_probe: Some((10, 11)),
+      activity: None,
+    };
+    let mut payload = MODEL_47_PAYLOAD;
+    // 1500 + 5000 = 0x1964, 15°C
+    payload[10] = 0x64;
+    payload[11] = 0x19;
+    let mut device = BroodminderDevice::build_broodminder_device(&payload).unwrap();
+    assert_eq!(device.temperature_probe2_c, None);
+
+    device.update_with_model(&payload, Some(&info));
+    assert_eq!(
+      device.temperature_probe1_c,
+      Some(device.realtime_temperature_c)
+    );
+    assert_eq!(device.temperature_probe2_c, Some(15.0));
+    assert_eq!(device.state_reading(2).temperature_probe2_c, Some(15.0));
+  }
+
+  #[test]
+  fn weight_is_only_decoded_for_scales() {
+    let device = BroodminderDevice::build_broodminder_device(&MODEL_47_PAYLOAD).unwrap();
+    assert_eq!(device.realtime_weight_kg, None);
+    assert_eq!(device.state_reading(2).weight_lbs, None);
+
+    let mut payload = MODEL_47_PAYLOAD;
+    payload[0] = 57;
+    let device = BroodminderDevice::build_broodminder_device(&payload).unwrap();
+    assert!(device.realtime_weight_kg.is_some());
+    assert!(device.state_reading(2).weight_lbs.is_some());
+  }
+
+  #[test]
+  fn scale_weight_decodes_tare_and_load() {
+    // (raw bytes low, high), kg, lb
+    let cases = [
+      ((0xFF, 0x7F), 0.0, 0.0),       // 32767, the empty scale
+      ((0xB7, 0x91), 45.36, 100.0),   // 37303
+      ((0x9B, 0x7F), -1.0, -2.2),     // 32667, a little under the tare
+      ((0x6F, 0xC3), 172.64, 380.61), // 50031, a full hive across the high byte
+    ];
+    for ((low, high), kg, lbs) in cases {
+      let mut payload = MODEL_47_PAYLOAD;
+      payload[0] = 57;
+      payload[19] = low;
+      payload[20] = high;
+      let reading = BroodminderDevice::build_broodminder_device(&payload)
+        .unwrap()
+        .state_reading(2);
+      assert_eq!(reading.weight_kg, Some(kg));
+      assert_eq!(reading.weight_lbs, Some(lbs));
+    }
+  }
+
+  #[test]
+  fn is_broodminder_rejects_truncated_payload() {
+    let data = HashMap::from([(653, MODEL_47_PAYLOAD[..MIN_PAYLOAD_LEN - 1].to_vec())]);
+    assert!(!BroodminderDevice::is_broodminder(&data, None));
+
+    let data = HashMap::from([(653, vec![])]);
+    assert!(!BroodminderDevice::is_broodminder(&data, None));
+  }
+
+  #[test]
+  fn truncated_payload_is_a_parse_error() {
+    let truncated = &MODEL_47_PAYLOAD[..MIN_PAYLOAD_LEN - 1];
+    assert!(matches!(
+      BroodminderDevice::build_broodminder_device(truncated),
+      Err(Error::Parse(_))
+    ));
+    assert!(matches!(
+      BroodminderDevice::build_broodminder_device(&[]),
+      Err(Error::Parse(_))
+    ));
+
+    let mut device = BroodminderDevice::build_broodminder_device(&MODEL_47_PAYLOAD).unwrap();
+    let before = device.elapsed1;
+    assert!(matches!(device.update(truncated), Err(Error::Parse(_))));
+    assert_eq!(device.elapsed1, before);
+  }
+
+  #[test]
+  fn downsampler_publishes_the_window_mean() {
+    let reading = |temperature_c: f64, weight_lbs: f64| StateReading {
+      temperature_c: Some(temperature_c),
+      temperature_f: Some(temperature_c * 9.0 / 5.0 + 32.0),
+      weight_lbs: Some(weight_lbs),
+      ..Default::default()
+    };
+
+    let mut downsampler = Downsampler::default();
+    assert!(downsampler.add(reading(20.0, 100.0), 0, 60000).is_none());
+    assert!(downsampler
+      .add(reading(22.0, 101.0), 30000, 60000)
+      .is_none());
+    let mean = downsampler.add(reading(27.0, 105.0), 60000, 60000).unwrap();
+    assert_eq!(mean.temperature_c, Some(23.0));
+    assert_eq!(round_reading(mean.temperature_f.unwrap(), 2), 73.4);
+    assert_eq!(mean.weight_lbs, Some(102.0));
+    assert_eq!(mean.pressure_hpa, None);
+
+    // The next window starts from scratch
+    assert!(downsampler.add(reading(30.0, 90.0), 61000, 60000).is_none());
+    let mean = downsampler.add(reading(32.0, 92.0), 121000, 60000).unwrap();
+    assert_eq!(mean.temperature_c, Some(31.0));
+  }
+
+  #[test]
+  #[cfg(feature = "mqtt")]
+  fn first_seen_reading_skips_the_wait() {
+    let now = 1_700_000_000_000;
+    let settings = crate::brood_flow_config::parse("devices: []").unwrap();
+    let mut device = BroodminderDevice::build_broodminder_device(&MODEL_47_PAYLOAD).unwrap();
+    assert!(device.next_state_reading(&settings, now).is_some());
+
+    let settings = crate::brood_flow_config::parse("devices: []\ndownsample_secs: 300").unwrap();
+    let mut device = BroodminderDevice::build_broodminder_device(&MODEL_47_PAYLOAD).unwrap();
+    assert!(device.next_state_reading(&settings, now).is_some());
+
+    let settings =
+      crate::brood_flow_config::parse("devices: []\npublish_on_first_seen: false").unwrap();
+    let mut device = BroodminderDevice::build_broodminder_device(&MODEL_47_PAYLOAD).unwrap();
+    assert!(device.next_state_reading(&settings, now).is_none());
+    assert!(device.next_state_reading(&settings, now + 10000).is_none());
+    assert!(device.next_state_reading(&settings, now + 31000).is_some());
+  }
+
+  #[test]
+  fn unchanged_readings_are_held_with_publish_on_change_only() {
+    let now = 1_700_000_000_000;
+    let settings = crate::brood_flow_config::parse(
+      "devices: []\npublish_on_change_only: true\nmax_unchanged_secs: 600",
+    )
+    .unwrap();
+    let mut device = BroodminderDevice::build_broodminder_device(&MODEL_47_PAYLOAD).unwrap();
+    assert!(device.next_state_reading(&settings, now).is_some());
+
+    // Only the RSSI moved
+    device.rssi = Some(-70);
+    assert!(device.next_state_reading(&settings, now + 31000).is_none());
+
+    let mut payload = MODEL_47_PAYLOAD;
+    payload[3] = 0x00;
+    device.update(&payload).unwrap();
+    assert!(device.next_state_reading(&settings, now + 62000).is_some());
+
+    // Unchanged, but it's been max_unchanged_secs
+    assert!(device.next_state_reading(&settings, now + 93000).is_none());
+    assert!(device
+      .next_state_reading(&settings, now + 62000 + 600000)
+      .is_some());
+  }
+
+  #[test]
+  fn min_change_ignores_jitter() {
+    let now = 1_700_000_000_000;
+    let settings = crate::brood_flow_config::parse(
+      "devices: []\npublish_on_change_only: true\nmin_change:\n  temperature: 0.1",
+    )
+    .unwrap();
+    let mut device = BroodminderDevice::build_broodminder_device(&MODEL_47_PAYLOAD).unwrap();
+    let reading = device.next_state_reading(&settings, now).unwrap();
+    assert_eq!(reading.temperature_c, Some(26.5));
+
+    // Realtime temperatures are in hundredths of a degree, data[3] being the low byte
+    let mut payload = MODEL_47_PAYLOAD;
+    payload[3] += 5;
+    device.update(&payload).unwrap();
+    assert!(device.next_state_reading(&settings, now + 31000).is_none());
+
+    // Drifting on, 0.1°C from what was last published
+    payload[3] += 5;
+    device.update(&payload).unwrap();
+    let reading = device.next_state_reading(&settings, now + 62000).unwrap();
+    assert_eq!(reading.temperature_c, Some(26.6));
+
+    assert!(
+      crate::brood_flow_config::parse("devices: []\nmin_change:\n  temperature: 0.1").is_err()
+    );
+    assert!(crate::brood_flow_config::parse(
+      "devices: []\npublish_on_change_only: true\nmin_change:\n  temprature: 0.1"
+    )
+    .is_err());
+  }
+
+  // Publishes go out on their own tasks, collect whatever reached the client's queue
+  #[cfg(feature = "mqtt")]
+  async fn published(eventloop: &rumqttc::EventLoop) -> Vec<rumqttc::Publish> {
+    let mut messages = vec![];
+    let timeout = std::time::Duration::from_millis(50);
+    while let Ok(Ok(request)) = tokio::time::timeout(timeout, eventloop.requests_rx.recv()).await {
+      if let rumqttc::Request::Publish(publish) = request {
+        messages.push(publish);
+      }
+    }
+    messages
+  }
+
+  #[cfg(feature = "mqtt")]
+  async fn published_topics(eventloop: &rumqttc::EventLoop) -> Vec<String> {
+    let messages = published(eventloop).await;
+    messages.into_iter().map(|publish| publish.topic).collect()
+  }
+
+  #[cfg(feature = "mqtt")]
+  fn test_publisher() -> (Publisher, rumqttc::EventLoop) {
+    let options = rumqttc::MqttOptions::new("test", "localhost", 1883);
+    let (client, eventloop) = rumqttc::AsyncClient::new(options, 100);
+    let publisher = Publisher::new(
+      client,
+      None,
+      crate::brood_flow_config::RateLimitOverflow::Wait,
+      None,
+    );
+    (publisher, eventloop)
+  }
+
+  // A device as the decoder would have it, its id taken from the name it advertises
+  #[cfg(feature = "mqtt")]
+  fn named_device(payload: &[u8], local_name: &str) -> BroodminderDevice {
+    let mut device = BroodminderDevice::build_broodminder_device(payload).unwrap();
+    device.device_id = local_name.to_string();
+    device.local_name = local_name.to_string();
+    device
+  }
+
+  #[cfg(feature = "mqtt")]
+  fn payload_json(publish: &rumqttc::Publish) -> JsonValue {
+    json::parse(std::str::from_utf8(&publish.payload).unwrap()).unwrap()
+  }
+
+  // The JSON payload of the message whose topic ends with topic_suffix
+  #[cfg(feature = "mqtt")]
+  fn find_payload(messages: &[rumqttc::Publish], topic_suffix: &str) -> JsonValue {
+    let publish = messages
+      .iter()
+      .find(|publish| publish.topic.ends_with(topic_suffix))
+      .unwrap_or_else(|| panic!("Nothing was published to ...{}", topic_suffix));
+    payload_json(publish)
+  }
+
+  // One of the device's discovery configs, by the end of its topic, e.g. "Temp/config". A copy of
+  // the device sends it, so the next call isn't held back by the config rate limit
+  #[cfg(feature = "mqtt")]
+  async fn config_payload(
+    settings: &Configuration,
+    device: &BroodminderDevice,
+    topic_suffix: &str,
+  ) -> JsonValue {
+    let (publisher, eventloop) = test_publisher();
+    device
+      .clone()
+      .send_config_messages(&publisher, settings, TEST_NOW);
+    find_payload(&published(&eventloop).await, topic_suffix)
+  }
+
+  // The same for the device's state messages, sent after its config as they would be
+  #[cfg(feature = "mqtt")]
+  async fn state_payload(
+    settings: &Configuration,
+    device: &BroodminderDevice,
+    topic_suffix: &str,
+  ) -> JsonValue {
+    let (publisher, eventloop) = test_publisher();
+    let mut device = device.clone();
+    device.send_config_messages(&publisher, settings, TEST_NOW);
+    device.send_state_message(&publisher, settings, TEST_NOW);
+    let states: Vec<rumqttc::Publish> = published(&eventloop)
+      .await
+      .into_iter()
+      .filter(|publish| !publish.topic.ends_with("/config"))
+      .collect();
+    find_payload(&states, topic_suffix)
+  }
+
+  #[tokio::test]
+  #[cfg(feature = "mqtt")]
+  async fn state_messages_are_rate_limited() {
+    let now = 1_700_000_000_000;
+    let settings = crate::brood_flow_config::parse("devices: []").unwrap();
+    let (publisher, eventloop) = test_publisher();
+    let mut device = BroodminderDevice::build_broodminder_device(&MODEL_47_PAYLOAD).unwrap();
+    device.device_id = "47:01:01".to_string();
+
+    // Held until HA knows the entities
+    device.send_state_message(&publisher, &settings, now);
+    assert!(published_topics(&eventloop).await.is_empty());
+    device.send_config_messages(&publisher, &settings, now);
+    published(&eventloop).await;
+
+    device.send_state_message(&publisher, &settings, now);
+    assert_eq!(
+      published_topics(&eventloop).await,
+      [
+        "homeassistant/sensor/BM470101/state",
+        "homeassistant/sensor/BM470101/attributes"
+      ]
+    );
+
+    // Suppressed within the 30s window, published again after it
+    device.send_state_message(&publisher, &settings, now + 29000);
+    assert!(published_topics(&eventloop).await.is_empty());
+    device.send_state_message(&publisher, &settings, now + 31000);
+    assert_eq!(published_topics(&eventloop).await.len(), 2);
+  }
+
+  #[tokio::test]
+  #[cfg(feature = "mqtt")]
+  async fn config_messages_are_rate_limited() {
+    let now = 1_700_000_000_000;
+    let settings = crate::brood_flow_config::parse("devices: []").unwrap();
+    let (publisher, eventloop) = test_publisher();
+    let mut device = BroodminderDevice::build_broodminder_device(&MODEL_47_PAYLOAD).unwrap();
+    device.device_id = "47:01:01".to_string();
+
+    device.send_config_messages(&publisher, &settings, now);
+    assert_eq!(
+      published_topics(&eventloop).await,
+      [
+        "homeassistant/sensor/BM470101Temp/config",
+        "homeassistant/binary_sensor/BM470101BatteryLow/config"
+      ]
+    );
+
+    // Suppressed within the hour, published again after it
+    device.send_config_messages(&publisher, &settings, now + 3599000);
+    assert!(published_topics(&eventloop).await.is_empty());
+    device.send_config_messages(&publisher, &settings, now + 3601000);
+    assert_eq!(published_topics(&eventloop).await.len(), 2);
+  }
+
+  #[tokio::test]
+  #[cfg(feature = "mqtt")]
+  async fn low_battery_is_a_binary_sensor() {
+    let settings = crate::brood_flow_config::parse("devices: []").unwrap();
+    let device = named_device(&MODEL_47_PAYLOAD, "47:01:01");
+    let config = config_payload(&settings, &device, "BatteryLow/config").await;
+    assert_eq!(config["device_class"], "battery");
+    assert_eq!(config["payload_on"], "ON");
+    assert_eq!(
+      config["value_template"],
+      "{{ 'ON' if value_json.battery_percent < 20 else 'OFF' }}"
+    );
+    assert!(config["unit_of_measurement"].is_null());
+  }
+
+  #[test]
+  #[cfg(feature = "mqtt")]
+  fn firmware_version_is_published() {
+    let settings = crate::brood_flow_config::parse("devices: []").unwrap();
+    let device = BroodminderDevice::build_broodminder_device(&MODEL_47_PAYLOAD).unwrap();
+    assert_eq!(device.firmware_version(), "3.2");
+    assert_eq!(device.device_block(&settings)["sw_version"], "3.2");
+    assert_eq!(device.attributes(&settings).unwrap()["firmware"], "3.2");
+
+    let settings = crate::brood_flow_config::parse("devices: []\npublish_firmware: false").unwrap();
+    assert!(device.device_block(&settings)["sw_version"].is_null());
+    assert!(device.attributes(&settings).is_none());
+  }
+
+  #[test]
+  #[cfg(feature = "mqtt")]
+  fn devices_are_named_after_their_configured_name() {
+    let device = named_device(&MODEL_47_PAYLOAD, "47:01:01");
+    let settings = crate::brood_flow_config::parse("devices: []").unwrap();
+    assert_eq!(device.device_block(&settings)["name"], "47:01:01");
+    let settings =
+      crate::brood_flow_config::parse("devices:\n  - id: \"47:01:01\"\n    name: \"Hive 1\"")
+        .unwrap();
+    assert_eq!(device.device_block(&settings)["name"], "Hive 1");
+  }
+
+  #[tokio::test]
+  #[cfg(feature = "mqtt")]
+  async fn diagnostic_sensors_are_disabled_by_default() {
+    let mut device = named_device(&MODEL_47_PAYLOAD, "47:01:01");
+    device.rssi = Some(-70);
+    let rssi_config = |yaml: &str| {
+      let settings = crate::brood_flow_config::parse(yaml).unwrap();
+      let device = device.clone();
+      async move { config_payload(&settings, &device, "Rssi/config").await }
+    };
+
+    let config = rssi_config("devices: []").await;
+    assert_eq!(config["enabled_by_default"], false);
+    assert_eq!(config["entity_category"], "diagnostic");
+    assert_eq!(
+      rssi_config("devices: []\nenable_diagnostics: true").await["enabled_by_default"],
+      true
+    );
+    assert_eq!(
+      rssi_config(
+        "enable_diagnostics: true\ndevices:\n  - id: \"47:01:01\"\n    enable_diagnostics: false"
+      )
+      .await["enabled_by_default"],
+      false
+    );
+    assert!(
+      rssi_config("devices: []\ncategorize_diagnostics: false").await["entity_category"].is_null()
+    );
+  }
+
+  #[tokio::test]
+  #[cfg(feature = "mqtt")]
+  async fn publish_sensors_filters_config_and_state() {
+    let settings = crate::brood_flow_config::parse(
+      "publish_sensors: [\"weight\"]\ndevices:\n  - id: \"47:01:01\"\n    publish_sensors: [\"temperature\"]",
+    )
+    .unwrap();
+    let (publisher, eventloop) = test_publisher();
+    let mut device = named_device(&MODEL_47_PAYLOAD, "47:01:01");
+    device.send_config_messages(&publisher, &settings, TEST_NOW);
+    assert!(published(&eventloop)
+      .await
+      .iter()
+      .all(|publish| !publish.topic.ends_with("BatteryLow/config")));
+
+    let state = state_payload(&settings, &device, "/state").await;
+    assert!(state.has_key("temperature_c"));
+    assert!(!state.has_key("battery_percent"));
+  }
+
+  #[test]
+  fn publish_sensors_must_be_known_kinds() {
+    assert!(
+      crate::brood_flow_config::parse("devices: []\npublish_sensors: [\"humidty\"]").is_err()
+    );
+  }
+
+  #[tokio::test]
+  #[cfg(feature = "mqtt")]
+  async fn compressed_attributes_are_gzipped() {
+    use std::io::Read;
+
+    let settings = crate::brood_flow_config::parse(
+      "devices: []\ncompress_attributes: true\nrequire_config_before_state: false",
+    )
+    .unwrap();
+    let (publisher, eventloop) = test_publisher();
+    let mut device = named_device(&MODEL_47_PAYLOAD, "47:01:01");
+    device.send_state_message(&publisher, &settings, 1_700_000_000_000);
+
+    let messages = published(&eventloop).await;
+    let attributes = messages
+      .iter()
+      .find(|publish| publish.topic == "homeassistant/sensor/BM470101/attributes/gzip")
+      .unwrap();
+    let mut decoded = String::new();
+    flate2::read::GzDecoder::new(&attributes.payload[..])
+      .read_to_string(&mut decoded)
+      .unwrap();
+    assert_eq!(json::parse(&decoded).unwrap()["firmware"], "3.2");
+  }
+
+  #[tokio::test]
+  #[cfg(feature = "mqtt")]
+  async fn object_id_comes_from_the_address() {
+    let mut device = named_device(&MODEL_47_PAYLOAD, "47:01:01");
+    device.address = "5E:00:00:00:00:01".to_string();
+    let object_id = |yaml: &str| {
+      let settings = crate::brood_flow_config::parse(yaml).unwrap();
+      let device = device.clone();
+      async move { config_payload(&settings, &device, "Temp/config").await["object_id"].clone() }
+    };
+
+    assert_eq!(
+      object_id("devices: []").await,
+      "bm_5e0000000001_temperature"
+    );
+    assert_eq!(
+      object_id("devices:\n  - id: \"47:01:01\"\n    object_id: \"hive_1\"").await,
+      "hive_1_temperature"
+    );
+  }
+
+  #[tokio::test]
+  #[cfg(feature = "mqtt")]
+  async fn weight_is_published_in_kg_and_optionally_lbs() {
+    let settings =
+      crate::brood_flow_config::parse("devices: []\npublish_both_weight_units: true").unwrap();
+    let mut payload = MODEL_47_PAYLOAD;
+    payload[0] = 57;
+    // 2000 + 32767 = 0x87CF, 20.00 kg
+    payload[19] = 0xCF;
+    payload[20] = 0x87;
+    let device = named_device(&payload, "57:01:01");
+
+    let kg = config_payload(&settings, &device, "Weight/config").await;
+    let lbs = config_payload(&settings, &device, "WeightLbs/config").await;
+    assert_eq!(kg["unit_of_measurement"], "kg");
+    assert_eq!(kg["value_template"], "{{ value_json.weight_kg }}");
+    assert_eq!(lbs["unit_of_measurement"], "lb");
+    assert_eq!(lbs["value_template"], "{{ value_json.weight_lbs }}");
+    assert_ne!(kg["unique_id"], lbs["unique_id"]);
+
+    let reading = device.state_reading(2);
+    assert_eq!(reading.weight_kg, Some(20.0));
+    assert_eq!(reading.weight_lbs, Some(44.09));
+  }
+
+  #[cfg(feature = "mqtt")]
+  #[test]
+  fn topic_id_can_come_from_the_mac_address() {
+    let mut device = BroodminderDevice::build_broodminder_device(&MODEL_47_PAYLOAD).unwrap();
+    device.device_id = "47:01:01".to_string();
+
+    let settings = crate::brood_flow_config::parse("devices: []").unwrap();
+    assert_eq!(
+      device.state_topic(&settings),
+      "homeassistant/sensor/BM470101/state"
+    );
+
+    let settings = crate::brood_flow_config::parse("topic_id_source: mac\ndevices: []").unwrap();
+    // Without an address there's only the local name to go on
+    assert_eq!(
+      device.state_topic(&settings),
+      "homeassistant/sensor/BM470101/state"
+    );
+    device.address = "5E:00:00:00:00:01".to_string();
+    assert_eq!(
+      device.state_topic(&settings),
+      "homeassistant/sensor/BM5E0000000001/state"
+    );
+    assert_eq!(
+      device.config_topic(&settings, &TEMPERATURE),
+      "homeassistant/sensor/BM5E0000000001Temp/config"
+    );
+  }
+
+  #[tokio::test]
+  #[cfg(feature = "mqtt")]
+  async fn dual_probe_model_has_a_sensor_per_probe() {
+    let settings = crate::brood_flow_config::parse("devices: []").unwrap();
+    let mut payload = MODEL_47_PAYLOAD;
+    payload[0] = 52;
+    // 1500 + 5000 = 0x1964, 15°C
+    payload[10] = 0x64;
+    payload[11] = 0x19;
+    let device = named_device(&payload, "52:01:01");
+    assert_eq!(device.state_reading(2).temperature_probe1_c, Some(26.5));
+    assert_eq!(device.state_reading(2).temperature_probe2_c, Some(15.0));
+
+    let probe1 = config_payload(&settings, &device, "/BM520101Probe1/config").await;
+    let probe2 = config_payload(&settings, &device, "/BM520101Probe2/config").await;
+    assert_eq!(probe1["unique_id"], "520101_temperature_probe1");
+    assert_eq!(probe2["unique_id"], "520101_temperature_probe2");
+    assert_eq!(
+      probe1["value_template"],
+      "{{ value_json.temperature_probe1_c }}"
+    );
+    assert_eq!(
+      probe2["value_template"],
+      "{{ value_json.temperature_probe2_c }}"
+    );
+    assert_eq!(probe2["device_class"], "temperature");
+    assert_eq!(probe2["unit_of_measurement"], "°C");
+
+    // Only the second probe's layout is a guess
+    assert!(probe1["enabled_by_default"].is_null());
+    assert_eq!(probe2["enabled_by_default"], false);
+    let settings =
+      crate::brood_flow_config::parse("devices: []\nenable_unconfirmed_sensors: true").unwrap();
+    let probe2 = config_payload(&settings, &device, "/BM520101Probe2/config").await;
+    assert!(probe2["enabled_by_default"].is_null());
+  }
+
+  #[tokio::test]
+  #[cfg(feature = "mqtt")]
+  async fn display_precision_is_suggested_to_ha() {
+    let device = named_device(&MODEL_47_PAYLOAD, "47:01:01");
+    let precision = |yaml: &str| {
+      let settings = crate::brood_flow_config::parse(yaml).unwrap();
+      let device = device.clone();
+      async move {
+        config_payload(&settings, &device, "Temp/config").await["suggested_display_precision"]
+          .clone()
+      }
+    };
+
+    assert!(precision("devices: []").await.is_null());
+    assert_eq!(precision("display_precision: 1\ndevices: []").await, 1);
+    assert_eq!(
+      precision("display_precision: 1\ndevices:\n  - id: \"47:01:01\"\n    display_precision: 0")
+        .await,
+      0
+    );
+  }
+
+  #[tokio::test]
+  #[cfg(feature = "mqtt")]
+  async fn single_state_message_carries_every_reading_and_attribute() {
+    let now = 1_700_000_000_000;
+    let settings = crate::brood_flow_config::parse(
+      "single_state_message: true\npublish_both_weight_units: true\ndevices: []",
+    )
+    .unwrap();
+    let (publisher, eventloop) = test_publisher();
+    let mut payload = MODEL_47_PAYLOAD;
+    payload[0] = 57;
+    let mut device = BroodminderDevice::build_broodminder_device(&payload).unwrap();
+    device.device_id = "57:01:01".to_string();
+    device.record_source("hci0".to_string(), Some(-60), now);
+
+    device.send_config_messages(&publisher, &settings, now);
+    let configs = published(&eventloop).await;
+    device.send_state_message(&publisher, &settings, now);
+    let states = published(&eventloop).await;
+
+    // One publish, with every key a sensor's value_template reads
+    assert_eq!(states.len(), 1);
+    assert_eq!(states[0].topic, "homeassistant/sensor/BM570101/state");
+    let state = payload_json(&states[0]);
+    assert_eq!(state["attributes"]["firmware"], "3.2");
+    assert_eq!(configs.len(), device.sensors(&settings).len());
+    for sensor in device.sensors(&settings) {
+      assert!(state.has_key(sensor.state_key), "{}", sensor.state_key);
+      let config = configs
+        .iter()
+        .find(|publish| publish.topic == device.config_topic(&settings, &sensor))
+        .unwrap();
+      let config = payload_json(config);
+      assert_eq!(config["state_topic"], "homeassistant/sensor/BM570101/state");
+      assert_eq!(config["json_attributes_topic"], config["state_topic"]);
+      assert!(config["value_template"]
+        .as_str()
+        .unwrap()
+        .contains(&format!("value_json.{} ", sensor.state_key)));
+    }
+  }
+
+  #[tokio::test]
+  #[cfg(feature = "mqtt")]
+  async fn topic_per_value_publishes_bare_values() {
+    let now = 1_700_000_000_000;
+    let settings = crate::brood_flow_config::parse("topic_per_value: true\ndevices: []").unwrap();
+    let (publisher, eventloop) = test_publisher();
+    let mut device = BroodminderDevice::build_broodminder_device(&MODEL_47_PAYLOAD).unwrap();
+    device.device_id = "47:01:01".to_string();
+    device.record_source("hci0".to_string(), Some(-60), now);
+
+    device.send_config_messages(&publisher, &settings, now);
+    let configs = published(&eventloop).await;
+    device.send_state_message(&publisher, &settings, now);
+    let states: Vec<(String, String)> = published(&eventloop)
+      .await
+      .into_iter()
+      .map(|publish| {
+        let payload = String::from_utf8(publish.payload.to_vec()).unwrap();
+        (publish.topic, payload)
+      })
+      .collect();
+
+    let state = |key: &str| {
+      let topic = format!("homeassistant/sensor/BM470101/state/{}", key);
+      states
+        .iter()
+        .find(|(state_topic, _)| *state_topic == topic)
+        .map(|(_, payload)| payload.as_str())
+    };
+    assert_eq!(state("temperature_c"), Some("26.5"));
+    assert_eq!(state("battery_percent"), Some("88"));
+    assert_eq!(state("rssi"), Some("-60"));
+
+    for config in configs {
+      let config = payload_json(&config);
+      let state_topic = config["state_topic"].as_str().unwrap();
+      assert!(state_topic.starts_with("homeassistant/sensor/BM470101/state/"));
+      if config["payload_on"].is_null() {
+        assert!(config["value_template"].is_null());
+      } else {
+        assert_eq!(
+          config["value_template"],
+          "{{ 'ON' if value | float < 20 else 'OFF' }}"
+        );
+      }
+    }
+
+    assert!(crate::brood_flow_config::parse(
+      "topic_per_value: true\nsingle_state_message: true\ndevices: []"
+    )
+    .is_err());
+  }
+
+  #[test]
+  fn temperature_units_are_chosen_independently() {
+    let settings = crate::brood_flow_config::parse(
+      "devices: []\ntemperature_unit: fahrenheit\naggregated_temperature_unit: celsius",
+    )
+    .unwrap();
+    let mut device = BroodminderDevice::build_broodminder_device(&MODEL_47_PAYLOAD).unwrap();
+    device.device_id = "47:01:01".to_string();
+
+    let temperatures: Vec<(&str, &str, &str)> = device
+      .sensors(&settings)
+      .iter()
+      .filter(|sensor| sensor.kind == "temperature")
+      .map(|sensor| (sensor.id, sensor.state_key, sensor.unit))
+      .collect();
+    assert_eq!(
+      temperatures,
+      [
+        ("temperature", "temperature_f", "°F"),
+        ("aggregated_temperature", "aggregated_temperature_c", "°C"),
+      ]
+    );
+
+    let reading = device
+      .next_state_reading(&settings, 1_700_000_000_000)
+      .unwrap();
+    assert_eq!(reading.temperature_f, Some(79.7));
+    assert_eq!(reading.aggregated_temperature_c, Some(26.48));
+    assert_eq!(reading.aggregated_temperature_f, None);
+
+    // Without aggregated_temperature_unit the aggregated temperature isn't published at all
+    let settings = crate::brood_flow_config::parse("devices: []").unwrap();
+    let mut device = BroodminderDevice::build_broodminder_device(&MODEL_47_PAYLOAD).unwrap();
+    let reading = device
+      .next_state_reading(&settings, 1_700_000_000_000)
+      .unwrap();
+    assert!(!reading.to_json().has_key("aggregated_temperature_c"));
+  }
+
+  #[test]
+  fn history_keeps_the_latest_readings() {
+    let mut device = BroodminderDevice::build_broodminder_device(&MODEL_47_PAYLOAD).unwrap();
+    device.record_history(1_700_000_000_000, 0);
+    assert!(device.history.is_empty());
+
+    for (second, temperature_c) in [(0, 20.0), (10, 21.0), (20, 22.5)] {
+      device.realtime_temperature_c = temperature_c;
+      device.record_history(1_700_000_000_000 + second * 1000, 2);
+    }
+    let temperatures: Vec<Option<f64>> = device
+      .history
+      .iter()
+      .map(|(_, reading)| reading.temperature_c)
+      .collect();
+    assert_eq!(temperatures, [Some(21.0), Some(22.5)]);
+    assert!(device.describe_history(1_700_000_030_000, 1)[0]
+      .starts_with("20s ago: {\"temperature_c\":21,"));
+  }
+
+  #[tokio::test]
+  #[cfg(feature = "mqtt")]
+  async fn config_can_be_requested_after_a_delay() {
+    let settings = crate::brood_flow_config::parse("devices: []").unwrap();
+    let (publisher, eventloop) = test_publisher();
+    let mut device = BroodminderDevice::build_broodminder_device(&MODEL_47_PAYLOAD).unwrap();
+    device.device_id = "47:01:01".to_string();
+    let start = 1_700_000_000_000;
+    device.send_config_messages(&publisher, &settings, start);
+    assert!(!published(&eventloop).await.is_empty());
+
+    // Ten minutes in, a reconnect asks for config a minute from now
+    let reconnected = start + 600_000;
+    device.request_config_after(reconnected, 60_000);
+    device.send_config_messages(&publisher, &settings, reconnected + 30_000);
+    assert!(published(&eventloop).await.is_empty());
+    device.send_config_messages(&publisher, &settings, reconnected + 61_000);
+    assert!(!published(&eventloop).await.is_empty());
+  }
+
+  #[test]
+  fn implausible_packets_are_caught() {
+    assert_eq!(BroodminderDevice::implausible(&MODEL_47_PAYLOAD), None);
+
+    let corrupt = |index: usize, byte: u8| {
+      let mut payload = MODEL_47_PAYLOAD;
+      payload[index] = byte;
+      BroodminderDevice::implausible(&payload)
+    };
+    assert_eq!(corrupt(2, 0), Some("firmware major version 0".to_string()));
+    assert_eq!(corrupt(4, 0xE8), Some("battery 232%".to_string()));
+    // 0xFFE2 is 605.06°C
+    assert_eq!(
+      corrupt(9, 0xFF),
+      Some("realtime temperature 605.06°C".to_string())
+    );
+    assert!(corrupt(8, 0x00).is_some());
+    assert!(BroodminderDevice::implausible(&MODEL_47_PAYLOAD[..10]).is_some());
+  }
+
+  #[test]
+  #[cfg(feature = "mqtt")]
+  fn expire_after_follows_the_device_cadence() {
+    let settings = crate::brood_flow_config::parse("devices: []").unwrap();
+    let mut device = BroodminderDevice::build_broodminder_device(&MODEL_47_PAYLOAD).unwrap();
+    let now = 1_700_000_000_000;
+    // Not heard twice yet, three of the 30s state intervals
+    device.record_source("hci0".to_string(), None, now);
+    assert_eq!(device.expire_after_secs(&settings), 90);
+
+    // Heard every 10s, quicker than it's published
+    for i in 1..=4 {
+      device.record_source("hci0".to_string(), None, now + i * 10000);
+    }
+    assert_eq!(device.expire_after_secs(&settings), 90);
+
+    // Then every 2 minutes, the average catches up over a few advertisements
+    for i in 1..=10 {
+      device.record_source("hci0".to_string(), None, now + 40000 + i * 120000);
+    }
+    assert_eq!(device.advertisement_interval_ms, Some(113_804));
+    assert_eq!(device.expire_after_secs(&settings), 342);
+
+    let settings = crate::brood_flow_config::parse(
+      "devices: []
+downsample_secs: 600
+expire_grace_factor: 1.5",
+    )
+    .unwrap();
+    assert_eq!(device.expire_after_secs(&settings), 900);
+    assert!(crate::brood_flow_config::parse(
+      "devices: []
+expire_grace_factor: 0.5"
+    )
+    .is_err());
+  }
+
+  #[test]
+  fn elapsed_counter_going_back_counts_a_reset() {
+    let settings = crate::brood_flow_config::parse(
+      "devices: []
+publish_resets: true",
+    )
+    .unwrap();
+    let mut payload = MODEL_47_PAYLOAD;
+    payload[5] = 0x10;
+    payload[6] = 0x02;
+    let mut device = BroodminderDevice::build_broodminder_device(&payload).unwrap();
+    assert_eq!((device.elapsed(), device.resets), (0x0210, 0));
+
+    // An older advertisement arriving late
+    payload[5] = 0x0F;
+    device.update(&payload).unwrap();
+    assert_eq!(device.resets, 0);
+
+    // Power cycled
+    payload[5] = 0x01;
+    payload[6] = 0x00;
+    device.update(&payload).unwrap();
+    assert_eq!(device.resets, 1);
+    assert_eq!(device.published_state_reading(&settings).resets, Some(1.0));
+    assert!(device.sensors(&settings).contains(&RESETS));
+
+    assert!(is_reset(600, 0));
+    assert!(!is_reset(u16::MAX, 3));
+    assert!(!is_reset(0, u16::MAX));
+  }
+
+  #[tokio::test]
+  #[cfg(feature = "mqtt")]
+  async fn configured_models_publish_their_sensors() {
+    let settings = crate::brood_flow_config::parse(
+      "devices: []\nmodels:\n  - model: 99\n    sensors:\n      - id: co2\n        byte: 12\n        unit: ppm\n        device_class: carbon_dioxide",
+    )
+    .unwrap();
+    let mut payload = MODEL_47_PAYLOAD;
+    payload[0] = 99;
+    payload[12] = 0x20;
+    payload[13] = 0x03;
+    let device = named_device(&payload, "99:01:01");
+
+    let config = config_payload(&settings, &device, "BM990101co2/config").await;
+    assert_eq!(config["unit_of_measurement"], "ppm");
+    assert_eq!(config["device_class"], "carbon_dioxide");
+    assert_eq!(config["value_template"], "{{ value_json.co2 }}");
+    assert_eq!(
+      state_payload(&settings, &device, "/state").await["co2"],
+      800
+    );
+  }
+
+  #[tokio::test]
+  #[cfg(feature = "mqtt")]
+  async fn last_seen_is_a_timestamp_sensor() {
+    let settings = crate::brood_flow_config::parse("devices: []\npublish_last_seen: true").unwrap();
+    let mut device = named_device(&MODEL_47_PAYLOAD, "47:01:01");
+    device.record_source("hci0".to_string(), None, TEST_NOW);
+
+    let config = config_payload(&settings, &device, "LastSeen/config").await;
+    assert_eq!(config["device_class"], "timestamp");
+    assert!(config["state_class"].is_null());
+    assert!(config["expire_after"].is_null());
+    assert_eq!(
+      state_payload(&settings, &device, "/state").await["last_seen"],
+      "2023-11-14T22:13:20Z"
+    );
+  }
+
+  #[test]
+  fn signal_quality_smooths_the_rssi() {
+    let settings = crate::brood_flow_config::parse(
+      "devices: []
+publish_signal_quality: true",
+    )
+    .unwrap();
+    let mut device = BroodminderDevice::build_broodminder_device(&MODEL_47_PAYLOAD).unwrap();
+    assert_eq!(device.signal_quality(&settings), None);
+    assert!(!device.sensors(&settings).contains(&SIGNAL_QUALITY));
+
+    device.record_source("hci0".to_string(), Some(-60), 1000);
+    assert_eq!(device.signal_quality(&settings), Some(80.0));
+    // One faded advertisement only moves it a little
+    device.record_source("hci0".to_string(), Some(-90), 2000);
+    assert_eq!(device.signal_quality(&settings), Some(50.0));
+    for at in 3..=12 {
+      device.record_source("hci0".to_string(), Some(-40), at * 1000);
+    }
+    // The old samples have dropped out, and it stops at 100%
+    assert_eq!(device.signal_quality(&settings), Some(100.0));
+    assert_eq!(
+      device
+        .published_state_reading(&settings)
+        .signal_quality_percent,
+      Some(100.0)
+    );
+    assert!(device.sensors(&settings).contains(&SIGNAL_QUALITY));
+
+    assert!(crate::brood_flow_config::parse(
+      "devices: []
+signal_quality_min_dbm: -50
+signal_quality_max_dbm: -60"
+    )
+    .is_err());
+  }
+
+  #[tokio::test]
+  #[cfg(feature = "mqtt")]
+  async fn model_is_published_once_with_the_config() {
+    let settings = crate::brood_flow_config::parse("devices: []\npublish_model: true").unwrap();
+    let (publisher, eventloop) = test_publisher();
+    let mut device = named_device(&MODEL_47_PAYLOAD, "47:01:01");
+    device.send_config_messages(&publisher, &settings, TEST_NOW);
+
+    let messages = published(&eventloop).await;
+    let config = find_payload(&messages, "Model/config");
+    let state_topic = "homeassistant/sensor/BM470101/state/model";
+    assert_eq!(config["state_topic"], state_topic);
+    assert_eq!(config["entity_category"], "diagnostic");
+    assert!(config["value_template"].is_null());
+    assert!(config["state_class"].is_null());
+    assert!(config["expire_after"].is_null());
+
+    let model = messages
+      .iter()
+      .find(|publish| publish.topic == state_topic)
+      .unwrap();
+    assert!(model.retain);
+    assert_eq!(&model.payload[..], device.model_name().as_bytes());
+    assert_eq!(config["device"]["model"], device.model_name().as_str());
+  }
+
+  #[test]
+  fn weight_models_are_configurable() {
+    let mut payload = MODEL_47_PAYLOAD;
+    payload[0] = 58;
+    payload[19] = 0x31;
+    payload[20] = 0x88;
+    let default = crate::brood_flow_config::parse("devices: []").unwrap();
+    assert!(default.model_info(58).is_none());
+    assert!(default.model_info(57).unwrap().weight);
+
+    let settings = crate::brood_flow_config::parse(
+      "devices: []
+weight_models: [57, 58]",
+    )
+    .unwrap();
+    let device =
+      BroodminderDevice::build_with_model_info(&payload, settings.model_info(58)).unwrap();
+    assert_eq!(device.realtime_weight_kg, Some(20.98));
+    assert!(device.sensors(&settings).contains(&WEIGHT));
+    assert_eq!(device.model_name(), "Broodminder-W");
+
+    // Leaving 57 out of the list takes the weight off the W
+    let settings = crate::brood_flow_config::parse("devices: []\nweight_models: [58]").unwrap();
+    assert!(!settings.model_info(57).unwrap().weight);
+  }
+
+  #[test]
+  #[cfg(feature = "mqtt")]
+  fn snapshot_has_the_current_reading() {
+    let settings = crate::brood_flow_config::parse(
+      "devices: []
+publish_sensors: [\"temperature\"]",
+    )
+    .unwrap();
+    let mut device = BroodminderDevice::build_broodminder_device(&MODEL_47_PAYLOAD).unwrap();
+    device.device_id = "47:01:01".to_string();
+    device.address = "5E:00:00:00:00:01".to_string();
+    device.record_source("hci0".to_string(), Some(-60), 1_700_000_000_000);
+
+    let snapshot = device.snapshot(&settings);
+    assert_eq!(snapshot["device_id"], "47:01:01");
+    assert_eq!(snapshot["model"], "Broodminder-T");
+    assert_eq!(snapshot["last_seen"], "2023-11-14T22:13:20Z");
+    assert_eq!(snapshot["state"]["temperature_c"], 26.5);
+    // Only the published kinds
+    assert!(snapshot["state"]["battery_percent"].is_null());
+  }
+
+  #[test]
+  fn battery_is_clamped_to_100() {
+    let mut payload = MODEL_47_PAYLOAD;
+    payload[4] = 103;
+    // A fresh battery, not a corrupt packet
+    assert_eq!(BroodminderDevice::implausible(&payload), None);
+    let device = BroodminderDevice::build_broodminder_device(&payload).unwrap();
+
+    let settings = crate::brood_flow_config::parse("devices: []").unwrap();
+    assert_eq!(
+      device.published_state_reading(&settings).battery_percent,
+      Some(100.0)
+    );
+    let settings = crate::brood_flow_config::parse("devices: []\nclamp_battery: false").unwrap();
+    assert_eq!(
+      device.published_state_reading(&settings).battery_percent,
+      Some(103.0)
+    );
+  }
+
+  #[test]
+  fn weights_outside_the_range_are_skipped_or_clamped() {
+    let mut payload = MODEL_47_PAYLOAD;
+    payload[0] = 57;
+    // A little under the tare
+    payload[19] = 0x9B;
+    payload[20] = 0x7F;
+    let mut device = BroodminderDevice::build_broodminder_device(&payload).unwrap();
+
+    let settings = crate::brood_flow_config::parse("devices: []").unwrap();
+    let reading = device.published_state_reading(&settings);
+    assert_eq!((reading.weight_kg, reading.weight_lbs), (None, None));
+    device.check_weight_range(&settings);
+    assert!(device.weight_out_of_range);
+    device.calibration.weight_offset_kg = 1.0;
+    device.check_weight_range(&settings);
+    assert!(!device.weight_out_of_range);
+    device.calibration.weight_offset_kg = 0.0;
+    let settings =
+      crate::brood_flow_config::parse("devices: []\nweight_out_of_range: clamp").unwrap();
+    let reading = device.published_state_reading(&settings);
+    assert_eq!(
+      (reading.weight_kg, reading.weight_lbs),
+      (Some(0.0), Some(0.0))
+    );
+    let settings = crate::brood_flow_config::parse("devices: []\nweight_min_kg: -5").unwrap();
+    assert!(device
+      .published_state_reading(&settings)
+      .weight_kg
+      .is_some());
+
+    assert!(
+      crate::brood_flow_config::parse("devices: []\nweight_min_kg: 50\nweight_max_kg: 50").is_err()
+    );
+  }
+
+  #[test]
+  fn calibration_offsets_the_published_reading() {
+    let mut payload = MODEL_47_PAYLOAD;
+    payload[0] = 57;
+    let mut device = BroodminderDevice::build_broodminder_device(&payload).unwrap();
+    let settings = crate::brood_flow_config::parse("devices: []").unwrap();
+    let uncalibrated = device.published_state_reading(&settings);
+
+    device.calibration = Calibration {
+      temperature_offset_c: -1.0,
+      weight_offset_kg: 2.0,
+    };
+    let reading = device.published_state_reading(&settings);
+    let offset = |reading: Option<f64>, by: f64| reading.map(|value| value + by);
+    assert_eq!(
+      reading.temperature_c,
+      offset(uncalibrated.temperature_c, -1.0)
+    );
+    assert_eq!(
+      reading.temperature_f,
+      offset(uncalibrated.temperature_f, -1.8)
+    );
+    assert_eq!(reading.weight_kg, offset(uncalibrated.weight_kg, 2.0));
+    assert_eq!(
+      reading.weight_lbs,
+      offset(uncalibrated.weight_lbs, 2.0 * f64::from(LBS_PER_KG))
+    );
+  }
+
+  #[test]
+  fn fixed_cadence_publishes_the_latest_reading_every_tick() {
+    let settings = crate::brood_flow_config::parse("devices: []\nfixed_cadence_secs: 10").unwrap();
+    let now = 1_700_000_000_000;
+    let mut device = BroodminderDevice::build_broodminder_device(&MODEL_47_PAYLOAD).unwrap();
+    device.record_source("hci0".to_string(), Some(-60), now);
+    // No rate limit or first seen hold back, the ticks decide
+    assert!(device.next_state_reading(&settings, now).is_some());
+    assert!(device.next_state_reading(&settings, now + 10_000).is_some());
+
+    // Quiet for longer than expire_after (30s)
+    assert!(!device.is_stale(&settings, now + 30_000));
+    assert!(device.is_stale(&settings, now + 31_000));
+
+    for invalid in [
+      "fixed_cadence_secs: 0",
+      "fixed_cadence_secs: 60\ndownsample_secs: 60",
+      "fixed_cadence_secs: 60\npublish_on_change_only: true",
+    ] {
+      let yaml = format!("devices: []\n{}", invalid);
+      assert!(
+        crate::brood_flow_config::parse(&yaml).is_err(),
+        "{}",
+        invalid
+      );
+    }
+  }
+
+  #[tokio::test]
+  #[cfg(feature = "mqtt")]
+  async fn activity_is_published_with_an_optional_swarm_alert() {
+    let mut payload = MODEL_47_PAYLOAD;
+    payload[0] = 63;
+    payload[12] = 75;
+    let device = named_device(&payload, "63:01:01");
+    assert_eq!(device.activity, Some(75));
+    assert_eq!(device.state_reading(2).activity, Some(75.0));
+    // Other models leave the byte alone
+    assert_eq!(
+      BroodminderDevice::build_broodminder_device(&MODEL_47_PAYLOAD)
+        .unwrap()
+        .activity,
+      None
+    );
+
+    let settings = crate::brood_flow_config::parse("devices: []").unwrap();
+    let ids: Vec<&str> = device.sensors(&settings).iter().map(|s| s.id).collect();
+    assert!(ids.contains(&"activity") && !ids.contains(&"swarm"));
+
+    let settings = crate::brood_flow_config::parse("devices: []\nswarm_threshold: 60").unwrap();
+    let config = config_payload(&settings, &device, "Swarm/config").await;
+    assert_eq!(config["device_class"], "problem");
+    assert_eq!(
+      config["value_template"],
+      "{{ 'ON' if value_json.activity >= 60 else 'OFF' }}"
+    );
+  }
+
+  #[test]
+  fn temperature_delta_is_against_the_ambient_device() {
+    let settings =
+      crate::brood_flow_config::parse("devices: []\nambient_device_id: \"47:00:09\"").unwrap();
+    let mut ambient = BroodminderDevice::build_broodminder_device(&MODEL_47_PAYLOAD).unwrap();
+    ambient.device_id = "47:00:09".to_string();
+    let mut hive = BroodminderDevice::build_broodminder_device(&MODEL_47_PAYLOAD).unwrap();
+    hive.device_id = "47:01:01".to_string();
+    assert!(hive.sensors(&settings).contains(&TEMPERATURE_DELTA));
+    assert!(!ambient.sensors(&settings).contains(&TEMPERATURE_DELTA));
+
+    hive.set_ambient_temperature(&settings, Some(hive.realtime_temperature_c - 10.0));
+    let delta = hive.state_reading(2).temp_delta_c.unwrap();
+    assert!((delta - 10.0).abs() < 1e-6);
+    ambient.set_ambient_temperature(&settings, Some(5.0));
+    assert_eq!(ambient.state_reading(2).temp_delta_c, None);
+
+    // No fresh ambient reading, no delta
+    hive.set_ambient_temperature(&settings, None);
+    assert_eq!(hive.state_reading(2).temp_delta_c, None);
+  }
+
+  #[test]
+  #[cfg(feature = "mqtt")]
+  fn manufacturer_data_lengths_are_an_attribute() {
+    let mut device = BroodminderDevice::build_broodminder_device(&MODEL_47_PAYLOAD).unwrap();
+    device.manufacturer_lengths = [(MANUFACTURER_ID, 25), (76, 4)].into_iter().collect();
+    let settings = crate::brood_flow_config::parse("devices: []").unwrap();
+    assert!(device.attributes(&settings).unwrap()["manufacturer_data"].is_null());
+
+    let settings =
+      crate::brood_flow_config::parse("devices: []\npublish_manufacturer_data: true").unwrap();
+    assert_eq!(
+      device.attributes(&settings).unwrap()["manufacturer_data"].dump(),
+      r#"{"76":4,"653":25}"#
+    );
+  }
 }
@@ -0,0 +1,47 @@
+// fixed_cadence_secs publishes each device's latest reading on a wall clock timer rather than as
+// advertisements arrive, e.g. every minute on the minute, for pipelines that want evenly spaced
+// samples. The other optional timers select! waits on are here too
+use chrono::prelude::Utc;
+use std::time::Duration;
+
+// The next tick of an optional timer, never for one that isn't set
+pub async fn tick(interval: &mut Option<tokio::time::Interval>) {
+  match interval {
+    Some(interval) => {
+      interval.tick().await;
+    }
+    None => std::future::pending().await,
+  }
+}
+
+// The first multiple of period_ms after now, in millisecond epoch time
+fn next_boundary(now: i64, period_ms: i64) -> i64 {
+  (now / period_ms + 1) * period_ms
+}
+
+// Waits for the next tick of the cadence and returns its time, never with no cadence set. A tick
+// is never returned twice, even if the clock is a hair behind the timer when it wakes
+pub async fn next_tick(period_ms: Option<i64>, last_tick: i64) -> i64 {
+  let period_ms = match period_ms {
+    Some(period_ms) => period_ms,
+    None => return std::future::pending().await,
+  };
+  let now = Utc::now().timestamp_millis();
+  let tick = next_boundary(now, period_ms).max(last_tick + period_ms);
+  tokio::time::sleep(Duration::from_millis((tick - now) as u64)).await;
+  tick
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn ticks_are_aligned_to_the_wall_clock() {
+    // 2023-11-14T22:13:20.5Z
+    let now = 1_700_000_000_500;
+    assert_eq!(next_boundary(now, 60_000), 1_700_000_040_000);
+    assert_eq!(next_boundary(1_700_000_040_000, 60_000), 1_700_000_100_000);
+    assert_eq!(next_boundary(now, 1000), 1_700_000_001_000);
+  }
+}
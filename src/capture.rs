@@ -0,0 +1,418 @@
+use crate::broodminder_device::{BroodminderDevice, MANUFACTURER_ID};
+use crate::error::Error;
+use std::collections::HashMap;
+use std::path::Path;
+
+// Reads BLE advertisements back out of btsnoop (e.g. an Android btsnoop_hci.log) and pcap captures,
+// so the decoder can be debugged against captures taken on hardware we don't own
+
+// An advertisement pulled out of a capture
+#[derive(Debug, PartialEq, Eq)]
+pub struct CapturedAdvertisement {
+  pub address: String,
+  pub local_name: Option<String>,
+  pub rssi: Option<i8>,
+  pub manufacturer_data: HashMap<u16, Vec<u8>>,
+}
+
+// btsnoop datalink types
+const BTSNOOP_HCI_UNENCAPSULATED: u32 = 1001;
+const BTSNOOP_HCI_UART: u32 = 1002;
+
+// pcap link types
+const LINKTYPE_BLUETOOTH_HCI_H4: u32 = 187;
+const LINKTYPE_BLUETOOTH_HCI_H4_WITH_PHDR: u32 = 201;
+const LINKTYPE_BLUETOOTH_LE_LL: u32 = 251;
+const LINKTYPE_BLUETOOTH_LE_LL_WITH_PHDR: u32 = 256;
+
+// HCI packet indicator for events, and the LE meta event/subevents carrying advertising reports
+const HCI_EVENT_PACKET: u8 = 0x04;
+const HCI_LE_META_EVENT: u8 = 0x3E;
+const LE_ADVERTISING_REPORT: u8 = 0x02;
+const LE_EXTENDED_ADVERTISING_REPORT: u8 = 0x0D;
+
+// Advertising data types
+const AD_SHORTENED_LOCAL_NAME: u8 = 0x08;
+const AD_COMPLETE_LOCAL_NAME: u8 = 0x09;
+const AD_MANUFACTURER_SPECIFIC: u8 = 0xFF;
+
+// Decodes every Broodminder advertisement in the capture and logs the resulting devices. Nothing is
+// published, this only exercises the decode path
+pub fn replay(path: &Path, accepted_models: Option<&[u8]>) -> Result<(), Error> {
+  let bytes = std::fs::read(path)?;
+  let advertisements = read_advertisements(&bytes)?;
+  info!(
+    "Read {} advertisements from {}",
+    advertisements.len(),
+    path.display()
+  );
+
+  let mut devices: HashMap<String, BroodminderDevice> = HashMap::new();
+  // Local names usually arrive in scan responses, separately from the manufacturer data
+  let mut names: HashMap<String, String> = HashMap::new();
+
+  for advertisement in advertisements {
+    if let Some(name) = &advertisement.local_name {
+      names.insert(advertisement.address.clone(), name.clone());
+    }
+
+    if !BroodminderDevice::is_broodminder(&advertisement.manufacturer_data, accepted_models) {
+      continue;
+    }
+
+    let data = match advertisement.manufacturer_data.get(&MANUFACTURER_ID) {
+      Some(data) => data,
+      None => {
+        warn!(
+          "Skipping advertisement from {} without manufacturer {} data",
+          advertisement.address, MANUFACTURER_ID
+        );
+        continue;
+      }
+    };
+    let decoded = match devices.remove(&advertisement.address) {
+      Some(mut device) => device.update(data).map(|_| device),
+      None => BroodminderDevice::build_broodminder_device(data),
+    };
+    let device = match decoded {
+      Ok(device) => devices
+        .entry(advertisement.address.clone())
+        .or_insert(device),
+      Err(error) => {
+        warn!(
+          "Skipping advertisement from {}: {}",
+          advertisement.address, error
+        );
+        continue;
+      }
+    };
+    device.device_id = names
+      .get(&advertisement.address)
+      .cloned()
+      .unwrap_or_else(|| advertisement.address.clone());
+    info!(
+      "{} (rssi {:?}): {:?}",
+      advertisement.address, advertisement.rssi, device
+    );
+  }
+
+  info!("Decoded {} Broodminder devices", devices.len());
+  Ok(())
+}
+
+// Parses a btsnoop or pcap capture, detected from its header
+pub fn read_advertisements(bytes: &[u8]) -> Result<Vec<CapturedAdvertisement>, Error> {
+  if bytes.starts_with(b"btsnoop\0") {
+    read_btsnoop(bytes)
+  } else if bytes.len() >= 4 {
+    read_pcap(bytes)
+  } else {
+    Err(Error::Parse(
+      "Capture is too short to be btsnoop or pcap".to_string(),
+    ))
+  }
+}
+
+fn read_btsnoop(bytes: &[u8]) -> Result<Vec<CapturedAdvertisement>, Error> {
+  // Header: "btsnoop\0", version, datalink type, all big endian
+  let datalink = read_u32_be(bytes, 12)?;
+  if datalink != BTSNOOP_HCI_UNENCAPSULATED && datalink != BTSNOOP_HCI_UART {
+    return Err(Error::Parse(format!(
+      "Unsupported btsnoop datalink type {}",
+      datalink
+    )));
+  }
+
+  let mut advertisements = Vec::new();
+  let mut offset = 16;
+  // Records: original length, included length, flags, drops, timestamp, then the packet
+  while offset + 24 <= bytes.len() {
+    let included_len = read_u32_be(bytes, offset + 4)? as usize;
+    let flags = read_u32_be(bytes, offset + 8)?;
+    let packet = bytes
+      .get(offset + 24..offset + 24 + included_len)
+      .ok_or_else(|| Error::Parse("Truncated btsnoop record".to_string()))?;
+    offset += 24 + included_len;
+
+    if datalink == BTSNOOP_HCI_UART {
+      parse_h4_packet(packet, &mut advertisements);
+    } else if flags & 0x03 == 0x03 {
+      // Unencapsulated captures flag received events with bits 0 (received) and 1 (command/event)
+      parse_hci_event(packet, &mut advertisements);
+    }
+  }
+
+  Ok(advertisements)
+}
+
+fn read_pcap(bytes: &[u8]) -> Result<Vec<CapturedAdvertisement>, Error> {
+  // Either microsecond or nanosecond magic, written in the capturing machine's byte order
+  let big_endian = match read_u32_be(bytes, 0)? {
+    0xA1B2C3D4 | 0xA1B23C4D => true,
+    0xD4C3B2A1 | 0x4D3CB2A1 => false,
+    _ => return Err(Error::Parse("Not a btsnoop or pcap capture".to_string())),
+  };
+  let read_u32 = |offset: usize| -> Result<u32, Error> {
+    let value = read_u32_be(bytes, offset)?;
+    Ok(if big_endian {
+      value
+    } else {
+      value.swap_bytes()
+    })
+  };
+
+  let link_type = read_u32(20)?;
+  let mut advertisements = Vec::new();
+  let mut offset = 24;
+  // Records: seconds, sub-seconds, included length, original length, then the packet
+  while offset + 16 <= bytes.len() {
+    let included_len = read_u32(offset + 8)? as usize;
+    let packet = bytes
+      .get(offset + 16..offset + 16 + included_len)
+      .ok_or_else(|| Error::Parse("Truncated pcap record".to_string()))?;
+    offset += 16 + included_len;
+
+    match link_type {
+      LINKTYPE_BLUETOOTH_HCI_H4 => parse_h4_packet(packet, &mut advertisements),
+      // A 4 byte direction pseudo-header precedes the H4 packet
+      LINKTYPE_BLUETOOTH_HCI_H4_WITH_PHDR if packet.len() > 4 => {
+        parse_h4_packet(&packet[4..], &mut advertisements)
+      }
+      LINKTYPE_BLUETOOTH_LE_LL => parse_link_layer_packet(packet, &mut advertisements),
+      // A 10 byte radio pseudo-header (channel, signal, flags...) precedes the link layer packet
+      LINKTYPE_BLUETOOTH_LE_LL_WITH_PHDR if packet.len() > 10 => {
+        parse_link_layer_packet(&packet[10..], &mut advertisements)
+      }
+      LINKTYPE_BLUETOOTH_HCI_H4_WITH_PHDR | LINKTYPE_BLUETOOTH_LE_LL_WITH_PHDR => {}
+      _ => {
+        return Err(Error::Parse(format!(
+          "Unsupported pcap link type {}",
+          link_type
+        )))
+      }
+    }
+  }
+
+  Ok(advertisements)
+}
+
+// An H4 packet is an HCI packet prefixed with its packet type
+fn parse_h4_packet(packet: &[u8], advertisements: &mut Vec<CapturedAdvertisement>) {
+  if packet.first() == Some(&HCI_EVENT_PACKET) {
+    parse_hci_event(&packet[1..], advertisements);
+  }
+}
+
+// Pulls the reports out of an HCI LE (extended) advertising report event, ignoring other events
+fn parse_hci_event(event: &[u8], advertisements: &mut Vec<CapturedAdvertisement>) {
+  if event.len() < 4 || event[0] != HCI_LE_META_EVENT {
+    return;
+  }
+
+  let subevent = event[2];
+  let num_reports = event[3];
+  let mut offset = 4;
+  for _ in 0..num_reports {
+    let report = match subevent {
+      // Event type, address type, address, data length, data, rssi
+      LE_ADVERTISING_REPORT => event.get(offset + 8).and_then(|&data_len| {
+        let data_len = data_len as usize;
+        let data = event.get(offset + 9..offset + 9 + data_len)?;
+        let rssi = *event.get(offset + 9 + data_len)? as i8;
+        let address = format_address(event.get(offset + 2..offset + 8)?);
+        Some((address, data, Some(rssi), 10 + data_len))
+      }),
+      // Event type (2), address type, address, phys, sid, tx power, rssi, interval (2),
+      // direct address type, direct address, data length, data
+      LE_EXTENDED_ADVERTISING_REPORT => event.get(offset + 23).and_then(|&data_len| {
+        let data_len = data_len as usize;
+        let data = event.get(offset + 24..offset + 24 + data_len)?;
+        let rssi = *event.get(offset + 13)? as i8;
+        let address = format_address(event.get(offset + 3..offset + 9)?);
+        Some((address, data, Some(rssi), 24 + data_len))
+      }),
+      _ => None,
+    };
+
+    match report {
+      Some((address, data, rssi, report_len)) => {
+        advertisements.push(parse_advertising_data(address, data, rssi));
+        offset += report_len;
+      }
+      None => return,
+    }
+  }
+}
+
+// Advertising channel PDUs sniffed straight off the air, e.g. with an nRF sniffer
+fn parse_link_layer_packet(packet: &[u8], advertisements: &mut Vec<CapturedAdvertisement>) {
+  // Access address (4), PDU header (2), advertiser address (6), advertising data
+  if packet.len() < 12 {
+    return;
+  }
+
+  // ADV_IND, ADV_NONCONN_IND, SCAN_RSP and ADV_SCAN_IND all carry advertising data
+  let pdu_type = packet[4] & 0x0F;
+  if ![0, 2, 4, 6].contains(&pdu_type) {
+    return;
+  }
+
+  let pdu_len = packet[5] as usize;
+  if pdu_len < 6 || packet.len() < 6 + pdu_len {
+    return;
+  }
+
+  let address = format_address(&packet[6..12]);
+  advertisements.push(parse_advertising_data(
+    address,
+    &packet[12..6 + pdu_len],
+    None,
+  ));
+}
+
+// Walks the length/type/value advertising data structures
+fn parse_advertising_data(address: String, data: &[u8], rssi: Option<i8>) -> CapturedAdvertisement {
+  let mut advertisement = CapturedAdvertisement {
+    address,
+    local_name: None,
+    rssi,
+    manufacturer_data: HashMap::new(),
+  };
+
+  let mut offset = 0;
+  while offset < data.len() {
+    let len = data[offset] as usize;
+    if len == 0 || offset + 1 + len > data.len() {
+      break;
+    }
+
+    let ad_type = data[offset + 1];
+    let value = &data[offset + 2..offset + 1 + len];
+    match ad_type {
+      AD_SHORTENED_LOCAL_NAME | AD_COMPLETE_LOCAL_NAME => {
+        advertisement.local_name = Some(String::from_utf8_lossy(value).into_owned());
+      }
+      // The first two bytes are the little endian company id, the rest is what btleplug reports
+      AD_MANUFACTURER_SPECIFIC if value.len() >= 2 => {
+        let company_id = u16::from_le_bytes([value[0], value[1]]);
+        advertisement
+          .manufacturer_data
+          .insert(company_id, value[2..].to_vec());
+      }
+      _ => {}
+    }
+    offset += 1 + len;
+  }
+
+  advertisement
+}
+
+// Addresses are transmitted least significant byte first
+fn format_address(address: &[u8]) -> String {
+  address
+    .iter()
+    .rev()
+    .map(|byte| format!("{:02X}", byte))
+    .collect::<Vec<String>>()
+    .join(":")
+}
+
+fn read_u32_be(bytes: &[u8], offset: usize) -> Result<u32, Error> {
+  match bytes.get(offset..offset + 4) {
+    Some(&[a, b, c, d]) => Ok(u32::from_be_bytes([a, b, c, d])),
+    _ => Err(Error::Parse("Unexpected end of capture".to_string())),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  // Advertising data for a Broodminder: flags, complete local name and manufacturer data
+  fn advertising_data() -> Vec<u8> {
+    let mut data = vec![0x02, 0x01, 0x06, 0x09, AD_COMPLETE_LOCAL_NAME];
+    data.extend_from_slice(b"47:01:01");
+    let payload = [47u8, 2, 3, 0xE2, 88];
+    data.push(3 + payload.len() as u8);
+    data.push(AD_MANUFACTURER_SPECIFIC);
+    data.extend_from_slice(&653u16.to_le_bytes());
+    data.extend_from_slice(&payload);
+    data
+  }
+
+  // An H4 LE advertising report event containing the advertising data above
+  fn advertising_report() -> Vec<u8> {
+    let data = advertising_data();
+    let mut report = vec![
+      0x00,
+      0x00,
+      0x66,
+      0x55,
+      0x44,
+      0x33,
+      0x22,
+      0x11,
+      data.len() as u8,
+    ];
+    report.extend_from_slice(&data);
+    report.push(0xC4); // -60 dBm
+
+    let mut packet = vec![
+      HCI_EVENT_PACKET,
+      HCI_LE_META_EVENT,
+      (report.len() + 2) as u8,
+      LE_ADVERTISING_REPORT,
+      1,
+    ];
+    packet.extend_from_slice(&report);
+    packet
+  }
+
+  fn expected() -> CapturedAdvertisement {
+    CapturedAdvertisement {
+      address: "11:22:33:44:55:66".to_string(),
+      local_name: Some("47:01:01".to_string()),
+      rssi: Some(-60),
+      manufacturer_data: HashMap::from([(653, vec![47, 2, 3, 0xE2, 88])]),
+    }
+  }
+
+  #[test]
+  fn reads_btsnoop_advertising_report() {
+    let packet = advertising_report();
+    let mut capture = b"btsnoop\0".to_vec();
+    capture.extend_from_slice(&1u32.to_be_bytes());
+    capture.extend_from_slice(&BTSNOOP_HCI_UART.to_be_bytes());
+    capture.extend_from_slice(&(packet.len() as u32).to_be_bytes());
+    capture.extend_from_slice(&(packet.len() as u32).to_be_bytes());
+    capture.extend_from_slice(&1u32.to_be_bytes()); // Received
+    capture.extend_from_slice(&0u32.to_be_bytes());
+    capture.extend_from_slice(&0u64.to_be_bytes());
+    capture.extend_from_slice(&packet);
+
+    assert_eq!(read_advertisements(&capture).unwrap(), vec![expected()]);
+  }
+
+  #[test]
+  fn reads_little_endian_pcap_with_phdr() {
+    let mut packet = vec![0, 0, 0, 1]; // Direction pseudo-header
+    packet.extend_from_slice(&advertising_report());
+    let mut capture = Vec::new();
+    capture.extend_from_slice(&0xA1B2C3D4u32.to_le_bytes());
+    capture.extend_from_slice(&[2, 0, 4, 0]);
+    capture.extend_from_slice(&[0; 8]);
+    capture.extend_from_slice(&65535u32.to_le_bytes());
+    capture.extend_from_slice(&LINKTYPE_BLUETOOTH_HCI_H4_WITH_PHDR.to_le_bytes());
+    capture.extend_from_slice(&[0; 8]);
+    capture.extend_from_slice(&(packet.len() as u32).to_le_bytes());
+    capture.extend_from_slice(&(packet.len() as u32).to_le_bytes());
+    capture.extend_from_slice(&packet);
+
+    assert_eq!(read_advertisements(&capture).unwrap(), vec![expected()]);
+  }
+
+  #[test]
+  fn rejects_unknown_format() {
+    assert!(read_advertisements(b"not a capture").is_err());
+  }
+}
@@ -0,0 +1,75 @@
+use clap::Parser;
+use std::path::PathBuf;
+
+// Command line options. Everything else is configured through configuration.yml
+#[derive(Debug, Parser)]
+#[command(about, version)]
+pub struct Cli {
+  #[arg(
+    long = "config",
+    value_name = "PATH",
+    help = "Config file or directory of config files, may be repeated. Later files override \
+            earlier ones, devices from every file are kept [default: configuration.yml]"
+  )]
+  pub config: Vec<PathBuf>,
+
+  #[arg(
+    long,
+    value_name = "NAME",
+    help = "Apply the settings under profiles.NAME in the configuration over the rest"
+  )]
+  pub profile: Option<String>,
+
+  #[arg(
+    long,
+    value_name = "FILE",
+    help = "Decode Broodminder advertisements from a btsnoop or pcap capture and log them, then exit"
+  )]
+  pub pcap: Option<PathBuf>,
+
+  #[arg(
+    long,
+    value_name = "COUNT",
+    conflicts_with = "pcap",
+    help = "Run COUNT synthetic devices through the pipeline instead of scanning bluetooth"
+  )]
+  pub simulate: Option<usize>,
+
+  #[arg(
+    long,
+    value_name = "MODEL",
+    conflicts_with_all = ["pcap", "simulate"],
+    help = "Publish the discovery config and one reading of a synthetic device of MODEL (e.g. 57) to \
+            the broker, then exit"
+  )]
+  pub test_publish: Option<u8>,
+
+  #[arg(
+    long,
+    conflicts_with_all = ["pcap", "test_publish"],
+    help = "Publish until every configured device has been heard once (or --once-timeout passes), \
+            then exit, e.g. for a cron job"
+  )]
+  pub once: bool,
+
+  #[arg(
+    long,
+    value_name = "SECS",
+    default_value_t = 120,
+    requires = "once",
+    help = "How long --once waits for the configured devices"
+  )]
+  pub once_timeout: u64,
+
+  #[arg(
+    long,
+    help = "Check the configuration and exit, nonzero if it's invalid (e.g. has a mistyped key)"
+  )]
+  pub validate_config: bool,
+
+  #[arg(
+    long,
+    help = "Print every configuration option with its type, default and description, then exit"
+  )]
+  pub print_config_schema: bool,
+}
@@ -0,0 +1,39 @@
+// Commands accepted on the command topic, the payload is the command name
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Command {
+  ResendConfig, // Republish every device's discovery config, e.g. after Home Assistant lost it
+  // Publish every device's current reading as one message to snapshot_topic. Sent for any publish
+  // on snapshot_command_topic, rather than parsed from the command topic
+  Snapshot,
+  // Sent by brood-flow itself when the broker connection comes back, never parsed from the topic.
+  // With reconnect_config_spread_secs the config is republished, staggered
+  Reconnected,
+}
+
+impl Command {
+  pub fn parse(payload: &[u8]) -> Option<Command> {
+    match std::str::from_utf8(payload).ok()?.trim() {
+      "resend_config" => Some(Command::ResendConfig),
+      _ => None,
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn parse_commands() {
+    assert_eq!(
+      Command::parse(b"resend_config"),
+      Some(Command::ResendConfig)
+    );
+    assert_eq!(
+      Command::parse(b" resend_config\n"),
+      Some(Command::ResendConfig)
+    );
+    assert_eq!(Command::parse(b"reboot"), None);
+    assert_eq!(Command::parse(&[0xFF, 0xFE]), None);
+  }
+}
@@ -0,0 +1,199 @@
+// models lets a model brood-flow doesn't decode yet be published from its byte layout in the
+// Broodminder manual, without waiting for a release, e.g.
+//   models:
+//     - model: 99
+//       sensors:
+//         - id: co2
+//           byte: 12
+//           device_class: carbon_dioxide
+//           unit: ppm
+// Each sensor reads `length` little endian bytes from `byte` and publishes raw * scale + offset. A
+// model brood-flow already decodes gets the configured sensors alongside its own
+use crate::broodminder_device::{swarm_sensor, Component, Sensor, SENSORS};
+use serde::Deserialize;
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ModelConfiguration {
+  pub model: u8, // The model number, the first byte of the advertisement
+  pub sensors: Vec<SensorDefinition>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SensorDefinition {
+  pub id: String, // The entity's suffix and its key in the state message, e.g. "co2"
+  pub byte: u8,   // Offset of the first (lowest) byte of the value in the advertisement
+  #[serde(default = "default_length")]
+  pub length: usize, // 1 or 2 bytes
+  #[serde(default)]
+  pub signed: bool, // If true, the bytes are a two's complement integer
+  #[serde(default = "default_scale")]
+  pub scale: f64,
+  #[serde(default)]
+  pub offset: f64,
+  pub device_class: Option<String>, // Home Assistant device class, e.g. "temperature"
+  pub unit: Option<String>,         // Home Assistant unit of measurement, e.g. "°C"
+}
+
+fn default_length() -> usize {
+  2
+}
+
+fn default_scale() -> f64 {
+  1.0
+}
+
+impl SensorDefinition {
+  // The value in an advertisement, None if it's too short to have it
+  pub fn decode(&self, data: &[u8]) -> Option<f64> {
+    let start = usize::from(self.byte);
+    let raw = match (data.get(start..start + self.length)?, self.signed) {
+      ([byte], false) => f64::from(*byte),
+      ([byte], true) => f64::from(*byte as i8),
+      ([low, high], false) => f64::from(u16::from_le_bytes([*low, *high])),
+      ([low, high], true) => f64::from(i16::from_le_bytes([*low, *high])),
+      _ => return None,
+    };
+    Some(raw * self.scale + self.offset)
+  }
+
+  // The entity published for it. The configuration lasts the whole run, so its strings are leaked
+  // to be as 'static as the built in sensors'
+  fn sensor(&self) -> Sensor {
+    fn leak(value: &str) -> &'static str {
+      Box::leak(value.to_string().into_boxed_str())
+    }
+    let id = leak(&self.id);
+    Sensor {
+      id,
+      kind: id,
+      state_key: id,
+      topic: id,
+      component: Component::Sensor,
+      device_class: self.device_class.as_deref().map(leak),
+      unit: leak(self.unit.as_deref().unwrap_or("")),
+      diagnostic: false,
+      // Whoever configured it knows their own layout
+      unconfirmed: false,
+    }
+  }
+}
+
+// A configured sensor of one model, ready to publish
+#[derive(Debug, Clone)]
+pub struct CustomSensor {
+  pub model: u8,
+  pub sensor: Sensor,
+  pub definition: SensorDefinition,
+}
+
+pub fn sensors(models: &[ModelConfiguration]) -> Vec<CustomSensor> {
+  models
+    .iter()
+    .flat_map(|model| {
+      model.sensors.iter().map(|definition| CustomSensor {
+        model: model.model,
+        sensor: definition.sensor(),
+        definition: definition.clone(),
+      })
+    })
+    .collect()
+}
+
+// Checks every model, so a mistake in the layout fails at startup rather than publishing nonsense
+pub fn validate(models: &[ModelConfiguration]) -> Result<(), String> {
+  for (i, model) in models.iter().enumerate() {
+    if models[..i].iter().any(|other| other.model == model.model) {
+      return Err(format!("models: model {} is listed twice", model.model));
+    }
+    for (j, definition) in model.sensors.iter().enumerate() {
+      let invalid = |reason: &str| {
+        format!(
+          "models: sensor \"{}\" of model {} {}",
+          definition.id, model.model, reason
+        )
+      };
+      if definition.id.is_empty()
+        || !definition
+          .id
+          .chars()
+          .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_')
+      {
+        return Err(invalid(
+          "needs an id of lowercase letters, digits and underscores",
+        ));
+      }
+      // The id is also the state key and the publish_sensors kind, which have to stay unambiguous
+      if SENSORS
+        .iter()
+        .chain([&swarm_sensor(0)])
+        .any(|sensor| [sensor.id, sensor.kind, sensor.state_key].contains(&definition.id.as_str()))
+      {
+        return Err(invalid("has the id of a built in sensor"));
+      }
+      if model.sensors[..j]
+        .iter()
+        .any(|other| other.id == definition.id)
+      {
+        return Err(invalid("is listed twice"));
+      }
+      if !(1..=2).contains(&definition.length) {
+        return Err(invalid("needs a length of 1 or 2 bytes"));
+      }
+      if !definition.scale.is_finite() || !definition.offset.is_finite() {
+        return Err(invalid("needs a finite scale and offset"));
+      }
+    }
+  }
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn definition(byte: u8, length: usize, signed: bool) -> SensorDefinition {
+    SensorDefinition {
+      id: "co2".to_string(),
+      byte,
+      length,
+      signed,
+      scale: 0.5,
+      offset: -10.0,
+      device_class: None,
+      unit: None,
+    }
+  }
+
+  #[test]
+  fn sensor_definitions_decode_their_bytes() {
+    let data = [99, 0x10, 0x27, 0xFE, 0xFF];
+    assert_eq!(definition(1, 2, false).decode(&data), Some(4990.0));
+    assert_eq!(definition(3, 2, true).decode(&data), Some(-11.0));
+    assert_eq!(definition(3, 1, false).decode(&data), Some(117.0));
+    assert_eq!(definition(4, 1, true).decode(&data), Some(-10.5));
+    // Past the end of a short advertisement
+    assert_eq!(definition(4, 2, false).decode(&data), None);
+  }
+
+  #[test]
+  fn validate_models() {
+    let model = |model: u8, definitions: Vec<SensorDefinition>| ModelConfiguration {
+      model,
+      sensors: definitions,
+    };
+    let named = |id: &str| SensorDefinition {
+      id: id.to_string(),
+      ..definition(1, 2, false)
+    };
+    assert!(validate(&[model(99, vec![named("co2"), named("lux_2")])]).is_ok());
+
+    assert!(validate(&[model(99, vec![]), model(99, vec![])]).is_err());
+    assert!(validate(&[model(99, vec![named("co2"), named("co2")])]).is_err());
+    assert!(validate(&[model(99, vec![named("CO2")])]).is_err());
+    assert!(validate(&[model(99, vec![named("weight_kg")])]).is_err());
+    assert!(validate(&[model(99, vec![named("swarm")])]).is_err());
+    assert!(validate(&[model(99, vec![definition(1, 4, false)])]).is_err());
+  }
+}
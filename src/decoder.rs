@@ -0,0 +1,154 @@
+use crate::ble_scanner::Advertisement;
+use crate::brood_flow_config::Configuration;
+use crate::broodminder_device::{BroodminderDevice, Reading};
+use crate::device_names::DeviceNames;
+use crate::health::Health;
+use chrono::prelude::Utc;
+use std::collections::HashMap;
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Mutex};
+use tokio::sync::broadcast;
+
+// Turns the advertisements from every adapter into readings, broadcast to every output
+pub struct Decoder {
+  // Cache of discovered devices by address, used to pick the best reading when several adapters
+  // hear a device. Shared with the SIGUSR1 dump and the apiary summary
+  pub devices: Arc<Mutex<HashMap<String, BroodminderDevice>>>,
+  device_names: DeviceNames,
+  settings: Arc<Configuration>,
+  health: Arc<Health>,
+  readings: broadcast::Sender<Reading>,
+}
+
+impl Decoder {
+  pub fn new(
+    settings: Arc<Configuration>,
+    health: Arc<Health>,
+    readings: broadcast::Sender<Reading>,
+  ) -> Decoder {
+    Decoder {
+      devices: Arc::default(),
+      device_names: DeviceNames::with_fixed_ids(&settings.mac_to_id),
+      settings,
+      health,
+      readings,
+    }
+  }
+
+  pub fn decode(&mut self, advertisement: Advertisement) {
+    let settings = &self.settings;
+    let mut devices = self.devices.lock().unwrap();
+    // Manufacturer id 653 isn't always enough to tell Broodminders from other devices
+    if !settings.accepts_name(&advertisement.local_name) {
+      debug!(
+        "Ignoring {}, its name doesn't match name_prefix_filter",
+        advertisement.local_name
+      );
+      return;
+    }
+    // There's no checksum, a packet corrupted over the air is only caught by its readings
+    if settings.drop_implausible_packets {
+      if let Some(reason) = BroodminderDevice::implausible(&advertisement.data) {
+        warn!(
+          "Dropping corrupt advertisement from {} ({})",
+          advertisement.local_name, reason
+        );
+        self.health.corrupt_packets.fetch_add(1, Ordering::Relaxed);
+        return;
+      }
+    }
+    self.health.advertisements.fetch_add(1, Ordering::Relaxed);
+    let address = advertisement.address.clone();
+    let now = Utc::now().timestamp_millis();
+
+    if let Some(device) = devices.get_mut(&address) {
+      // Another adapter may have just heard this device with a better signal
+      let accepted =
+        device.accepts_reading_from(&advertisement.adapter, advertisement.rssi, now, settings);
+      if !accepted {
+        debug!(
+          "Ignoring weaker reading of {} from {}",
+          device.device_id, advertisement.adapter
+        );
+        return;
+      }
+
+      // Weak advertisements may be relayed or corrupted, they only show the device is still there
+      if !settings.is_confident_rssi(&advertisement.local_name, advertisement.rssi) {
+        debug!(
+          "Ignoring weak reading of {} ({:?} dBm)",
+          device.device_id, advertisement.rssi
+        );
+        device.mark_seen(now);
+        return;
+      }
+
+      // The local name can change, e.g. once it populates after the first advertisements
+      if device.local_name != advertisement.local_name {
+        info!(
+          "{} is now named {}",
+          device.device_id, advertisement.local_name
+        );
+        self.device_names.release(&device.device_id);
+        device.device_id = self
+          .device_names
+          .resolve(&advertisement.local_name, &address);
+        device.local_name = advertisement.local_name;
+      }
+
+      // Update the previous object if we've already seen it
+      if let Err(error) = device.update(&advertisement.data) {
+        warn!(
+          "Ignoring advertisement from {}: {}",
+          device.device_id, error
+        );
+        return;
+      }
+      device.check_weight_range(settings);
+      device.manufacturer_lengths = advertisement.manufacturer_lengths;
+      device.record_source(advertisement.adapter, advertisement.rssi, now);
+      device.record_history(now, settings.history_len);
+      info!("Updated {}", device);
+      debug!("Updated device: {:?}", device);
+    } else if !settings.is_confident_rssi(&advertisement.local_name, advertisement.rssi) {
+      debug!(
+        "Ignoring weak first reading of {} ({:?} dBm)",
+        advertisement.local_name, advertisement.rssi
+      );
+      return;
+    } else {
+      // Instantiate an object
+      let info = settings.model_info(advertisement.data[0]);
+      let mut brood_data = match BroodminderDevice::build_with_model_info(&advertisement.data, info)
+      {
+        Ok(device) => device,
+        Err(error) => {
+          warn!(
+            "Ignoring advertisement from {}: {}",
+            advertisement.local_name, error
+          );
+          return;
+        }
+      };
+      brood_data.device_id = self
+        .device_names
+        .resolve(&advertisement.local_name, &address);
+      brood_data.local_name = advertisement.local_name;
+      brood_data.address = address.clone();
+      brood_data.check_weight_range(settings);
+      brood_data.manufacturer_lengths = advertisement.manufacturer_lengths;
+      brood_data.record_source(advertisement.adapter, advertisement.rssi, now);
+      brood_data.record_history(now, settings.history_len);
+
+      info!("New Broodminder device detected: {}", brood_data);
+      debug!("New device: {:?}", brood_data);
+      devices.insert(address.clone(), brood_data);
+      self.health.devices.store(devices.len(), Ordering::Relaxed);
+    }
+
+    // Having no subscribers (e.g. MQTT disabled) is fine, the reading is just dropped
+    let _ = self.readings.send(Reading {
+      device: devices[&address].clone(),
+    });
+  }
+}
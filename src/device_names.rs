@@ -0,0 +1,137 @@
+use crate::topics::canonical_id;
+use std::collections::HashMap;
+
+// Placeholder device id for advertisements whose local_name hasn't populated yet. Devices with it
+// are never published
+pub const UNKNOWN_DEVICE_ID: &str = "00:00:00";
+
+// Hands out device ids (used for display and topics) from advertised local names, making sure no
+// two physical sensors (MAC addresses) end up with the same id
+#[derive(Debug, Default)]
+pub struct DeviceNames {
+  owners: HashMap<String, String>, // Canonical device id -> the address it was given to
+  fixed: HashMap<String, String>,  // Address -> the device id mac_to_id gives it
+}
+
+impl DeviceNames {
+  // Sensors listed in mac_to_id always get their configured id, whatever their local name.
+  // Addresses are matched case-insensitively
+  pub fn with_fixed_ids(mac_to_id: &HashMap<String, String>) -> DeviceNames {
+    let fixed: HashMap<String, String> = mac_to_id
+      .iter()
+      .map(|(address, device_id)| (address.to_uppercase(), device_id.clone()))
+      .collect();
+    let owners = fixed
+      .iter()
+      .map(|(address, device_id)| (canonical_id(device_id), address.clone()))
+      .collect();
+    DeviceNames { owners, fixed }
+  }
+
+  // The device id for the sensor with this address. If another sensor already has the local name,
+  // a suffix is added ("47:01:01_2") so the two don't merge into one Home Assistant entity
+  pub fn resolve(&mut self, local_name: &str, address: &str) -> String {
+    if let Some(device_id) = self.fixed.get(&address.to_uppercase()) {
+      return device_id.clone();
+    }
+    if local_name == UNKNOWN_DEVICE_ID {
+      return local_name.to_string();
+    }
+
+    let mut device_id = local_name.to_string();
+    let mut suffix = 1;
+    loop {
+      // "47:01:01" and "470101" would share topics, so they count as the same name
+      match self.owners.get(&canonical_id(&device_id)) {
+        Some(owner) if owner != address => {
+          suffix += 1;
+          device_id = format!("{}_{}", local_name, suffix);
+        }
+        Some(_) => return device_id,
+        None => break,
+      }
+    }
+
+    if suffix > 1 {
+      warn!(
+        "{} ({}) has the same name as another sensor, publishing it as {}",
+        local_name, address, device_id
+      );
+    }
+    self
+      .owners
+      .insert(canonical_id(&device_id), address.to_string());
+    device_id
+  }
+
+  // Frees up a device id when its sensor is renamed
+  pub fn release(&mut self, device_id: &str) {
+    // Fixed ids stay reserved for their sensor
+    if !self.fixed.values().any(|fixed_id| fixed_id == device_id) {
+      self.owners.remove(&canonical_id(device_id));
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn resolve_suffixes_colliding_names() {
+    let mut names = DeviceNames::default();
+    assert_eq!(names.resolve("47:01:01", "AA:AA:AA:AA:AA:01"), "47:01:01");
+    assert_eq!(names.resolve("47:01:01", "AA:AA:AA:AA:AA:02"), "47:01:01_2");
+    assert_eq!(names.resolve("47:01:01", "AA:AA:AA:AA:AA:03"), "47:01:01_3");
+
+    // Each sensor keeps the id it was given
+    assert_eq!(names.resolve("47:01:01", "AA:AA:AA:AA:AA:02"), "47:01:01_2");
+    assert_eq!(names.resolve("47:01:01", "AA:AA:AA:AA:AA:01"), "47:01:01");
+  }
+
+  #[test]
+  fn resolve_leaves_unknown_names_alone() {
+    let mut names = DeviceNames::default();
+    assert_eq!(
+      names.resolve(UNKNOWN_DEVICE_ID, "AA:AA:AA:AA:AA:01"),
+      UNKNOWN_DEVICE_ID
+    );
+    assert_eq!(
+      names.resolve(UNKNOWN_DEVICE_ID, "AA:AA:AA:AA:AA:02"),
+      UNKNOWN_DEVICE_ID
+    );
+  }
+
+  #[test]
+  fn release_frees_the_name() {
+    let mut names = DeviceNames::default();
+    names.resolve("47:01:01", "AA:AA:AA:AA:AA:01");
+    names.release("47:01:01");
+    assert_eq!(names.resolve("47:01:01", "AA:AA:AA:AA:AA:02"), "47:01:01");
+  }
+
+  #[test]
+  fn fixed_ids_win_over_local_names() {
+    let mac_to_id = HashMap::from([("aa:aa:aa:aa:aa:01".to_string(), "hive-1".to_string())]);
+    let mut names = DeviceNames::with_fixed_ids(&mac_to_id);
+    assert_eq!(
+      names.resolve(UNKNOWN_DEVICE_ID, "AA:AA:AA:AA:AA:01"),
+      "hive-1"
+    );
+
+    // Nobody else can take the fixed id, even once it's released
+    names.release("hive-1");
+    assert_eq!(names.resolve("hive-1", "AA:AA:AA:AA:AA:02"), "hive-1_2");
+  }
+
+  #[test]
+  fn resolve_suffixes_names_that_share_topics() {
+    let mut names = DeviceNames::default();
+    assert_eq!(names.resolve("47:01:01", "AA:AA:AA:AA:AA:01"), "47:01:01");
+    assert_eq!(names.resolve("470101", "AA:AA:AA:AA:AA:02"), "470101_2");
+    assert_eq!(
+      names.resolve("BM-47-01-01", "AA:AA:AA:AA:AA:03"),
+      "BM-47-01-01_3"
+    );
+  }
+}
@@ -0,0 +1,93 @@
+// The ways brood-flow can fail, by what needs looking at. Each has its own exit code, so whatever
+// runs brood-flow (systemd, a script around --validate-config) can tell a bad configuration from a
+// missing adapter or an unreachable broker
+use config::ConfigError;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+  #[error("Invalid configuration: {0}")]
+  Config(#[from] ConfigError),
+  // No usable adapter, an adapter failing to scan, or nothing heard at all
+  #[error("Bluetooth: {0}")]
+  Ble(String),
+  // A capture file that isn't btsnoop or pcap, or is cut short, or an advertisement too short to
+  // decode
+  #[error("Couldn't parse: {0}")]
+  Parse(String),
+  // Setting up the connection (e.g. reading the TLS certificates) or connecting gave up
+  #[error("MQTT: {0}")]
+  Mqtt(String),
+  #[cfg(feature = "sqlite")]
+  #[error("SQLite: {0}")]
+  Sqlite(String),
+  #[error(transparent)]
+  Io(#[from] std::io::Error),
+}
+
+impl Error {
+  // 1 is left for failures outside these, e.g. a panic
+  pub fn exit_code(&self) -> u8 {
+    match self {
+      Error::Config(_) => 2,
+      Error::Ble(_) => 3,
+      Error::Parse(_) => 4,
+      Error::Mqtt(_) => 5,
+      #[cfg(feature = "sqlite")]
+      Error::Sqlite(_) => 6,
+      Error::Io(_) => 7,
+    }
+  }
+}
+
+impl From<btleplug::Error> for Error {
+  fn from(error: btleplug::Error) -> Error {
+    Error::Ble(error.to_string())
+  }
+}
+
+#[cfg(feature = "mqtt")]
+impl From<rumqttc::ConnectionError> for Error {
+  fn from(error: rumqttc::ConnectionError) -> Error {
+    Error::Mqtt(error.to_string())
+  }
+}
+
+#[cfg(feature = "mqtt")]
+impl From<rumqttc::ClientError> for Error {
+  fn from(error: rumqttc::ClientError) -> Error {
+    Error::Mqtt(error.to_string())
+  }
+}
+
+#[cfg(feature = "sqlite")]
+impl From<rusqlite::Error> for Error {
+  fn from(error: rusqlite::Error) -> Error {
+    Error::Sqlite(error.to_string())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::collections::HashSet;
+
+  #[test]
+  fn each_kind_of_error_exits_differently() {
+    let errors = [
+      Error::Config(ConfigError::Message("broker_port is missing".to_string())),
+      Error::Ble("no usable adapters".to_string()),
+      Error::Parse("truncated record".to_string()),
+      Error::Mqtt("connection refused".to_string()),
+      #[cfg(feature = "sqlite")]
+      Error::Sqlite("database is locked".to_string()),
+      Error::Io(std::io::Error::from(std::io::ErrorKind::NotFound)),
+    ];
+    let codes: HashSet<u8> = errors.iter().map(Error::exit_code).collect();
+    assert_eq!(codes.len(), errors.len());
+    assert!(!codes.contains(&0) && !codes.contains(&1));
+    assert_eq!(
+      errors[0].to_string(),
+      "Invalid configuration: broker_port is missing"
+    );
+  }
+}
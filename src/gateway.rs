@@ -0,0 +1,82 @@
+use crate::publisher::Publisher;
+use json::object;
+use rumqttc::{LastWill, QoS};
+
+pub const ONLINE: &str = "online";
+pub const OFFLINE: &str = "offline";
+
+// The message reporting whether brood-flow is connected, published as "online" on every connect and
+// registered with the broker as our last will so it flips to "offline" when we drop off.
+// It's retained unless availability_retain is false, so HA sees the current state as soon as it
+// subscribes, even after a restart
+pub fn availability_message(topic: &str, qos: QoS, retain: bool, online: bool) -> LastWill {
+  LastWill::new(topic, if online { ONLINE } else { OFFLINE }, qos, retain)
+}
+
+pub fn send_online_message(publisher: &Publisher, topic: &str, qos: QoS, retain: bool) {
+  let message = availability_message(topic, qos, retain, true);
+  publisher.publish(
+    message.topic,
+    message.qos,
+    message.retain,
+    message.message.to_vec(),
+    "availability",
+  );
+}
+
+// Home Assistant only creates devices for entities, so the gateway is registered as a device with a
+// single status sensor (which just shows the availability topic). Broodminder sensors then reference
+// it through `via_device`, giving a device hierarchy of gateway -> sensors in the HA UI.
+// The config is retained so HA picks the gateway back up after restarting.
+pub fn send_gateway_messages(
+  publisher: &Publisher,
+  gateway_id: &str,
+  availability_topic: &str,
+  discovery_prefix: &str,
+) {
+  let config_message = object! {
+    name: format!("{}_status", gateway_id),
+    device: {
+      identifiers: [gateway_id],
+      name: gateway_id,
+      manufacturer: "brood-flow",
+      model: "Broodminder MQTT gateway",
+      sw_version: env!("CARGO_PKG_VERSION"),
+    },
+    icon: "mdi:bee",
+    state_topic: availability_topic,
+    unique_id: format!("{}_status", gateway_id),
+  };
+  let config_topic = format!("{}/sensor/{}/config", discovery_prefix, gateway_id);
+  info!("Publishing gateway configuration for {}", gateway_id);
+
+  publisher.publish(
+    config_topic,
+    QoS::AtLeastOnce,
+    true,
+    config_message.dump(),
+    "gateway config",
+  );
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn availability_messages_are_retained() {
+    let online = availability_message("brood-flow/availability", QoS::AtLeastOnce, true, true);
+    assert_eq!(online.topic, "brood-flow/availability");
+    assert_eq!(&online.message[..], b"online");
+    assert_eq!(online.qos, QoS::AtLeastOnce);
+    assert!(online.retain);
+
+    let offline = availability_message("brood-flow/availability", QoS::ExactlyOnce, true, false);
+    assert_eq!(&offline.message[..], b"offline");
+    assert_eq!(offline.qos, QoS::ExactlyOnce);
+    assert!(offline.retain);
+
+    let unretained = availability_message("brood-flow/availability", QoS::AtLeastOnce, false, true);
+    assert!(!unretained.retain);
+  }
+}
@@ -0,0 +1,66 @@
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+
+// Counters shared with the Publisher, for the startup watchdog and the heartbeat log
+#[derive(Debug, Default)]
+pub struct Health {
+  pub advertisements: AtomicU64,  // Broodminder advertisements decoded
+  pub corrupt_packets: AtomicU64, // Advertisements dropped by drop_implausible_packets
+  pub devices: AtomicUsize,       // Distinct sensors heard
+  pub publishes: Arc<AtomicU64>,  // MQTT messages handed to the client, shared with the Publisher
+  pub mqtt_connected: AtomicBool,
+}
+
+impl Health {
+  // e.g. "3 devices, 120 advertisements, 45 publishes, MQTT connected". Corrupt packets are only
+  // mentioned once there are some
+  pub fn summary(&self, mqtt: bool) -> String {
+    let mut summary = format!(
+      "{} devices, {} advertisements",
+      self.devices.load(Ordering::Relaxed),
+      self.advertisements.load(Ordering::Relaxed)
+    );
+    let corrupt_packets = self.corrupt_packets.load(Ordering::Relaxed);
+    if corrupt_packets > 0 {
+      summary.push_str(&format!(" ({} corrupt)", corrupt_packets));
+    }
+    if mqtt {
+      summary.push_str(&format!(
+        ", {} publishes, MQTT {}",
+        self.publishes.load(Ordering::Relaxed),
+        if self.mqtt_connected.load(Ordering::Relaxed) {
+          "connected"
+        } else {
+          "disconnected"
+        }
+      ));
+    }
+    summary
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn summary_only_mentions_mqtt_when_used() {
+    let health = Health::default();
+    health.devices.store(3, Ordering::Relaxed);
+    health.advertisements.store(120, Ordering::Relaxed);
+    health.publishes.store(45, Ordering::Relaxed);
+    health.mqtt_connected.store(true, Ordering::Relaxed);
+
+    assert_eq!(
+      health.summary(true),
+      "3 devices, 120 advertisements, 45 publishes, MQTT connected"
+    );
+    assert_eq!(health.summary(false), "3 devices, 120 advertisements");
+
+    health.corrupt_packets.store(2, Ordering::Relaxed);
+    assert_eq!(
+      health.summary(false),
+      "3 devices, 120 advertisements (2 corrupt)"
+    );
+  }
+}
@@ -1,135 +1,651 @@
+// Most of the publishing logic (discovery, topics, rate limiting, downsampling, the cadence and
+// most of the configuration) is on the device and configuration types every output shares, but
+// only the MQTT output uses all of it. Without mqtt much of it goes unused
+#![cfg_attr(not(feature = "mqtt"), allow(dead_code))]
+
 #[macro_use]
 extern crate log;
 
+#[cfg(feature = "mqtt")]
+mod apiary_summary;
+mod ble_scanner;
+mod broker_url;
 mod brood_flow_config;
 mod broodminder_device;
+mod cadence;
+mod capture;
+mod cli;
+#[cfg(feature = "mqtt")]
+mod commands;
+mod config_schema;
+mod custom_models;
+mod decoder;
+mod device_names;
+mod error;
+#[cfg(feature = "mqtt")]
+mod gateway;
+mod health;
+#[cfg(feature = "mqtt")]
+mod mqtt_options;
+#[cfg(feature = "mqtt")]
+mod mqtt_sink;
+mod once;
+mod payload_template;
+#[cfg(feature = "mqtt")]
+mod publisher;
+mod pushgateway;
+#[cfg(feature = "rest")]
+mod rest;
+mod simulator;
+#[cfg(feature = "sqlite")]
+mod sqlite_sink;
+#[cfg(feature = "mqtt")]
+mod test_publish;
+#[cfg(feature = "mqtt")]
+mod topic_history;
+mod topics;
+mod webhook;
 
-use broodminder_device::BroodminderDevice;
-use btleplug::api::{Central, CentralEvent, Manager as _, Peripheral as _, ScanFilter};
-use btleplug::platform::{Adapter, Manager};
-use futures::stream::StreamExt;
-use rumqttc::{AsyncClient, MqttOptions};
+use ble_scanner::Advertisement;
+use brood_flow_config::Configuration;
+use broodminder_device::{BroodminderDevice, Reading};
+use btleplug::platform::Manager;
+use chrono::prelude::Utc;
+use clap::Parser;
+use cli::Cli;
+#[cfg(feature = "mqtt")]
+use commands::Command;
+#[cfg(not(feature = "mqtt"))]
+use config::ConfigError;
+use decoder::Decoder;
+use error::Error;
+use health::Health;
+use once::Once;
+#[cfg(feature = "mqtt")]
+use publisher::Publisher;
+#[cfg(feature = "mqtt")]
+use rumqttc::{AsyncClient, EventLoop};
 use std::collections::HashMap;
-use std::error::Error;
+use std::process::ExitCode;
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Mutex};
 use std::time::Duration;
-
-async fn get_central(manager: &Manager) -> Adapter {
-  let adapters = manager.adapters().await.unwrap();
-  adapters.into_iter().nth(0).unwrap()
-}
+use tokio::sync::{broadcast, mpsc};
+use webhook::Webhook;
 
 #[tokio::main]
-async fn main() -> Result<(), Box<dyn Error>> {
+async fn main() -> ExitCode {
   // Initialize logging at log level info by default
   env_logger::init_from_env(
     env_logger::Env::default().filter_or(env_logger::DEFAULT_FILTER_ENV, "info"),
   );
 
+  match run(Cli::parse()).await {
+    Ok(()) => ExitCode::SUCCESS,
+    Err(error) => {
+      error!("{}", error);
+      ExitCode::from(error.exit_code())
+    }
+  }
+}
+
+async fn run(args: Cli) -> Result<(), Error> {
+  if args.print_config_schema {
+    config_schema::print();
+    return Ok(());
+  }
+
   // Load configuration.yaml into our Configuration object
-  let settings = brood_flow_config::get_config().unwrap();
-  info!("Settings: {:?}", settings);
+  let settings = Arc::new(brood_flow_config::get_config(
+    &args.config,
+    args.profile.as_deref(),
+  )?);
+  if let Some(profile) = &args.profile {
+    info!("Using profile {}", profile);
+  }
+  info!("Configuration:\n{}", settings);
+  if settings.topic_namespace.is_some() && settings.publish_discovery {
+    info!(
+      "topic_namespace is set, Home Assistant's MQTT discovery prefix must be \"{}\"",
+      settings.discovery_prefix
+    );
+  }
+  if args.validate_config {
+    info!("Configuration is valid");
+    return Ok(());
+  }
+
+  if let Some(model) = args.test_publish {
+    #[cfg(feature = "mqtt")]
+    return test_publish::run(model, &settings).await;
+    #[cfg(not(feature = "mqtt"))]
+    return Err(Error::Config(ConfigError::Message(format!(
+      "--test-publish {} needs the mqtt feature",
+      model
+    ))));
+  }
+
+  // Unless told otherwise, only decode the models we know about, or have been told the layout of
+  let accepted_models = if settings.accept_unknown_models {
+    None
+  } else {
+    Some(settings.known_models.clone().unwrap_or_else(|| {
+      let mut models = broodminder_device::known_models();
+      models.extend(settings.models.iter().map(|model| model.model));
+      models.extend(settings.weight_models.iter().flatten());
+      models
+    }))
+  };
+
+  // Debugging against a capture doesn't need bluetooth or MQTT
+  if let Some(path) = &args.pcap {
+    return capture::replay(path, accepted_models.as_deref());
+  }
+
+  // Decoded readings are broadcast to every output, each subscribing independently
+  let (reading_tx, _) = broadcast::channel::<Reading>(100);
 
   // Set up the MQTT connection
-  // TODO: Be resilient to MQTT disconnections?
-  let mut mqttoptions = MqttOptions::new(
-    "brood-flow2",
-    settings.broker_host.unwrap(),
-    settings.broker_port.unwrap(),
-  );
-  mqttoptions.set_keep_alive(Duration::from_secs(5));
+  #[cfg(feature = "mqtt")]
+  let mut mqtt = if settings.mqtt_enabled {
+    let mqttoptions = mqtt_options::build_mqtt_options(&settings)?;
 
-  // Not sure why, but the client doesn't send if it's not marked as mutable here
-  #[allow(unused_mut)]
-  let (mut client, mut eventloop) = AsyncClient::new(mqttoptions, 10);
+    // All publishing goes through the Publisher, which applies the global rate limit
+    let (client, eventloop) = AsyncClient::new(mqttoptions, 10);
+    let publisher = Publisher::new(
+      client.clone(),
+      settings.max_publishes_per_sec,
+      settings.rate_limit_overflow,
+      settings.max_concurrent_publishes,
+    );
+    let (command_tx, command_rx) = mpsc::channel::<Command>(10);
+    mqtt_sink::start(
+      reading_tx.subscribe(),
+      command_rx,
+      publisher.clone(),
+      settings.clone(),
+    );
+    Some(Mqtt {
+      client,
+      eventloop,
+      publisher,
+      command_tx,
+      connected: false,
+      failed_attempts: 0,
+      ever_connected: false,
+      retry_at: None,
+    })
+  } else {
+    None
+  };
+  #[cfg(not(feature = "mqtt"))]
+  let mut mqtt: Option<Mqtt> = None;
+  #[cfg(not(feature = "mqtt"))]
+  if settings.mqtt_enabled {
+    warn!("mqtt_enabled is set, but brood-flow was built without the mqtt feature");
+  }
 
-  // Get the first bluetooth adapter and connect to the adapter
-  let btle_manager = Manager::new().await?;
-  let central = get_central(&btle_manager).await;
+  if let Some(url) = &settings.pushgateway_url {
+    pushgateway::start(url, reading_tx.subscribe(), settings.clone());
+  }
 
-  // Each adapter has an event stream, we fetch via events(),
-  // This will return what is essentially:
-  // Future<Result<Stream<Item=CentralEvent>>>.
-  let mut events = central.events().await?;
+  if let Some(path) = &settings.sqlite_path {
+    #[cfg(feature = "sqlite")]
+    sqlite_sink::start(path, reading_tx.subscribe(), settings.clone())?;
+    #[cfg(not(feature = "sqlite"))]
+    warn!(
+      "sqlite_path {} is set, but brood-flow was built without the sqlite feature",
+      path
+    );
+  }
 
-  // Start scanning for BTLE devices
-  // TODO: Add a scan filter?
-  central.start_scan(ScanFilter::default()).await?;
+  let webhook = Webhook::new(settings.webhook_url.clone());
 
-  // Cache of discovered devices, as we want to store when the last message was sent per device
-  let mut devices: HashMap<String, BroodminderDevice> = HashMap::new();
+  let (advertisement_tx, mut advertisement_rx) = mpsc::channel::<Advertisement>(100);
+  if let Some(count) = args.simulate {
+    // Synthetic devices stand in for the bluetooth adapters
+    simulator::start(count, advertisement_tx);
+  } else {
+    // Listen on every bluetooth adapter (or the configured subset), each adapter feeds the same
+    // channel so a device heard by more than one adapter ends up in a single entry
+    let btle_manager = Manager::new().await?;
+    let centrals = ble_scanner::get_centrals(&btle_manager, &settings.adapters).await?;
+    if centrals.is_empty() {
+      webhook
+        .notify_and_wait(
+          "adapter_error",
+          "No usable bluetooth adapters found".to_string(),
+        )
+        .await;
+      return Err(Error::Ble("No usable bluetooth adapters found".to_string()));
+    }
 
-  // Start a task to listen for BTLE events
-  tokio::task::spawn(async move {
-    info!("Listening for Broodminder events.");
-    // When events are received by the BTLE stream, process them
-    while let Some(event) = events.next().await {
-      // Right now, we only care about the Data Advertisements from the Broodminder devices
-      if let CentralEvent::ManufacturerDataAdvertisement {
-        id,
-        manufacturer_data,
-      } = event
-      {
-        // Ensure we're only reading data from Broodminder devices
-        if BroodminderDevice::is_broodminder(&manufacturer_data) {
-          let peripheral = central.peripheral(&id).await.unwrap();
-          let properties = peripheral.properties().await.unwrap();
-          let device_id = properties
-            .unwrap()
-            .local_name
-            .unwrap_or(String::from("00:00:00")); // Sometimes device ID doesn't correctly populate
-
-          if devices.contains_key(&device_id) {
-            // Update the previous object if we've already seen it
-            devices
-              .entry(device_id.clone())
-              .or_default()
-              .update(&manufacturer_data[&653]);
-            info!("Updated Device: {:?}", devices[&device_id]);
-          } else {
-            // Instantiate an object
-            let mut brood_data =
-              BroodminderDevice::build_broodminder_device(&manufacturer_data[&653]);
-            brood_data.device_id = device_id.clone();
-
-            info!("New Broodminder device detected: {:?}", brood_data);
-            devices.insert(device_id.clone(), brood_data);
-          }
+    for central in centrals {
+      let started =
+        ble_scanner::start_scanner(central, advertisement_tx.clone(), accepted_models.clone())
+          .await;
+      if let Err(error) = started {
+        webhook
+          .notify_and_wait(
+            "adapter_error",
+            format!("Couldn't start scanning: {}", error),
+          )
+          .await;
+        return Err(error);
+      }
+    }
+    drop(advertisement_tx);
+  }
+
+  let health = Arc::new(Health {
+    #[cfg(feature = "mqtt")]
+    publishes: mqtt
+      .as_ref()
+      .map(|mqtt| mqtt.publisher.sent_counter())
+      .unwrap_or_default(),
+    ..Default::default()
+  });
+  let mut decoder = Decoder::new(settings.clone(), health.clone(), reading_tx);
+  #[cfg(unix)]
+  dump_devices_on_sigusr1(decoder.devices.clone(), settings.decimal_places)?;
+  if let Some(port) = settings.rest_port {
+    #[cfg(feature = "rest")]
+    rest::start(port, decoder.devices.clone(), settings.clone()).await?;
+    #[cfg(not(feature = "rest"))]
+    warn!(
+      "rest_port is set ({}:{}), but brood-flow was built without the rest feature",
+      settings.rest_bind, port
+    );
+  }
+  #[cfg(feature = "mqtt")]
+  if let Some(mqtt) = &mqtt {
+    if settings.publish_summary {
+      apiary_summary::start(
+        decoder.devices.clone(),
+        mqtt.publisher.clone(),
+        settings.clone(),
+      );
+    }
+  }
+
+  #[cfg(feature = "mqtt")]
+  let uses_mqtt = mqtt.is_some();
+  #[cfg(not(feature = "mqtt"))]
+  let uses_mqtt = false;
+  // Logs a summary every heartbeat_secs, so a quiet log still shows brood-flow is alive. The first
+  // tick would be immediate, with nothing to report yet
+  let mut heartbeat = settings
+    .heartbeat_secs
+    .filter(|secs| *secs > 0)
+    .map(|secs| {
+      let every = Duration::from_secs(secs);
+      tokio::time::interval_at(tokio::time::Instant::now() + every, every)
+    });
 
-          // Send our config and state messages (these functions already handle rate limiting)
-          if settings.mqtt_enabled {
-            devices
-              .entry(device_id.clone())
-              .and_modify(|device| device.send_config_messages(client.clone()));
+  // Never hearing a single device usually means a bluetooth permission or adapter problem, which
+  // otherwise just looks like "no data". Exiting lets systemd (or similar) restart us
+  let startup_grace_period = Duration::from_secs(settings.startup_require_device_secs);
+  let startup_check = tokio::time::sleep(startup_grace_period);
+  tokio::pin!(startup_check);
+  let mut startup_checked = settings.startup_require_device_secs == 0;
 
-            devices
-              .entry(device_id.clone())
-              .and_modify(|device| device.send_state_message(client.clone()));
+  // With --once the run ends a moment after the last configured device is first heard, long enough
+  // for its reading to go out, or at the timeout
+  let mut once = args.once.then(|| Once::new(&settings.devices));
+  if once.as_ref().is_some_and(|once| !once.tracks_devices()) {
+    info!(
+      "--once: no devices with an id are configured, publishing everything heard for {}s",
+      args.once_timeout
+    );
+  }
+  let once_deadline = tokio::time::sleep(Duration::from_secs(args.once_timeout));
+  tokio::pin!(once_deadline);
+  let mut once_finishing = false;
+
+  let shutdown = shutdown_signal()?;
+  tokio::pin!(shutdown);
+
+  // Everything brood-flow waits on is handled here, so a failure anywhere (or a shutdown) stops the
+  // whole gateway in one place
+  loop {
+    tokio::select! {
+      advertisement = advertisement_rx.recv() => match advertisement {
+        Some(advertisement) => {
+          decoder.decode(advertisement);
+          if let Some(once) = &mut once {
+            if once.heard(&decoder.devices.lock().unwrap()) {
+              info!("--once: heard every configured device");
+              once_finishing = true;
+              once_deadline
+                .as_mut()
+                .reset(tokio::time::Instant::now() + ONCE_FLUSH_DELAY);
+            }
           }
         }
+        // Only the simulator stops, the scanners restart themselves
+        None => {
+          info!("No more advertisements, shutting down");
+          break;
+        }
+      },
+      event = poll_mqtt(&mut mqtt) => {
+        handle_mqtt(&mut mqtt, event, &webhook, &health, &settings).await?
+      }
+      _ = cadence::tick(&mut heartbeat) => info!("Heartbeat: {}", health.summary(uses_mqtt)),
+      _ = &mut startup_check, if !startup_checked => {
+        startup_checked = true;
+        if health.advertisements.load(Ordering::Relaxed) == 0 {
+          return Err(Error::Ble(format!(
+            "No Broodminder device heard within {}s of starting (startup_require_device_secs). \
+             Check the bluetooth adapter is up and brood-flow is allowed to scan with it",
+            startup_grace_period.as_secs()
+          )));
+        }
       }
+      _ = &mut once_deadline, if once.is_some() => {
+        match &once {
+          Some(once) if !once_finishing && once.tracks_devices() => warn!(
+            "--once: timed out after {}s without hearing {}",
+            args.once_timeout,
+            once.pending().join(", ")
+          ),
+          _ => {}
+        }
+        info!("--once: done, shutting down");
+        break;
+      }
+      _ = &mut shutdown => {
+        info!("Shutting down");
+        break;
+      }
+    }
+  }
+
+  #[cfg(feature = "mqtt")]
+  if let Some(mqtt) = mqtt {
+    mqtt.disconnect(&settings).await;
+  }
+  Ok(())
+}
+
+// How long --once keeps running after hearing the last device, for the outputs to publish it
+const ONCE_FLUSH_DELAY: Duration = Duration::from_secs(2);
+
+// Resolves on Ctrl-C, or SIGTERM (e.g. from systemd) on unix
+fn shutdown_signal() -> Result<impl std::future::Future<Output = ()>, Error> {
+  #[cfg(unix)]
+  let mut terminate = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())?;
+  Ok(async move {
+    #[cfg(unix)]
+    tokio::select! {
+      _ = tokio::signal::ctrl_c() => {}
+      _ = terminate.recv() => {}
+    }
+    #[cfg(not(unix))]
+    let _ = tokio::signal::ctrl_c().await;
+  })
+}
+
+// Without the mqtt feature there's never a connection to poll
+#[cfg(not(feature = "mqtt"))]
+type Mqtt = std::convert::Infallible;
+
+#[cfg(not(feature = "mqtt"))]
+async fn poll_mqtt(_: &mut Option<Mqtt>) -> Mqtt {
+  std::future::pending().await
+}
+
+#[cfg(not(feature = "mqtt"))]
+async fn handle_mqtt(
+  _: &mut Option<Mqtt>,
+  event: Mqtt,
+  _: &Webhook,
+  _: &Health,
+  _: &Configuration,
+) -> Result<(), Error> {
+  match event {}
+}
+
+// Logs every tracked device on SIGUSR1 (`kill -USR1 <pid>`), for a look at what brood-flow is
+// hearing on a headless gateway
+#[cfg(unix)]
+fn dump_devices_on_sigusr1(
+  devices: Arc<Mutex<HashMap<String, BroodminderDevice>>>,
+  decimal_places: u32,
+) -> Result<(), Error> {
+  use tokio::signal::unix::{signal, SignalKind};
+
+  let mut signals = signal(SignalKind::user_defined1())?;
+  tokio::task::spawn(async move {
+    while signals.recv().await.is_some() {
+      let now = Utc::now().timestamp_millis();
+      let devices = devices.lock().unwrap();
+      let mut sorted: Vec<&BroodminderDevice> = devices.values().collect();
+      sorted.sort_by(|a, b| a.device_id.cmp(&b.device_id));
+      let mut lines = Vec::new();
+      for device in sorted {
+        lines.push(format!("  {}", device.describe(now, decimal_places)));
+        // Only there with history_len
+        for line in device.describe_history(now, decimal_places) {
+          lines.push(format!("    {}", line));
+        }
+      }
+      info!("Tracking {} devices:\n{}", devices.len(), lines.join("\n"));
     }
   });
+  Ok(())
+}
 
-  // Pump the MQTT eventloop
-  loop {
-    let event = eventloop.poll().await;
+// How long to wait after an MQTT connection error before trying again
+#[cfg(feature = "mqtt")]
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+// How long a clean disconnect waits for the last messages to go out
+#[cfg(feature = "mqtt")]
+const DISCONNECT_TIMEOUT: Duration = Duration::from_secs(2);
+
+#[cfg(feature = "mqtt")]
+type MqttEvent = Result<rumqttc::Event, rumqttc::ConnectionError>;
+
+// The MQTT connection, pumped by the main loop
+#[cfg(feature = "mqtt")]
+struct Mqtt {
+  client: AsyncClient,
+  eventloop: EventLoop,
+  publisher: Publisher,
+  command_tx: mpsc::Sender<Command>,
+  // Only changes of connection state are sent to the webhook, not every retry
+  connected: bool,
+  // Connection errors since the last successful connect
+  failed_attempts: u32,
+  ever_connected: bool,
+  retry_at: Option<tokio::time::Instant>, // Set after an error, polling waits until then
+}
+
+#[cfg(feature = "mqtt")]
+impl Mqtt {
+  // The next event. The main loop drops this whenever another branch wins, so a reconnect delay
+  // still being waited out is kept for the next poll
+  async fn poll(&mut self) -> MqttEvent {
+    if let Some(retry_at) = self.retry_at {
+      tokio::time::sleep_until(retry_at).await;
+      self.retry_at = None;
+    }
+    self.eventloop.poll().await
+  }
+
+  async fn handle(
+    &mut self,
+    event: MqttEvent,
+    webhook: &Webhook,
+    health: &Health,
+    settings: &Configuration,
+  ) -> Result<(), Error> {
     match event {
       Ok(rumqttc::Event::Incoming(rumqttc::Incoming::ConnAck(msg))) => {
         info!("Connected to the broker!");
         debug!("Connected msg = {msg:?}");
+        self.connected = true;
+        health.mqtt_connected.store(true, Ordering::Relaxed);
+        self.failed_attempts = 0;
+        if self.ever_connected && self.command_tx.try_send(Command::Reconnected).is_err() {
+          warn!("Not republishing config after reconnecting, too many commands are queued");
+        }
+        self.ever_connected = true;
+        webhook.notify(
+          "mqtt_connected",
+          settings.broker_host.clone().unwrap_or_default(),
+        );
+
+        // The broker forgets our subscriptions when the session isn't kept, so subscribe on every
+        // connect rather than just the first one
+        self
+          .publisher
+          .subscribe(settings.command_topic.clone(), settings.command_qos.qos());
+        self.publisher.subscribe(
+          settings.snapshot_command_topic.clone(),
+          settings.command_qos.qos(),
+        );
+
+        gateway::send_online_message(
+          &self.publisher,
+          &settings.availability_topic,
+          settings.availability_qos.qos(),
+          settings.availability_retain,
+        );
+        match &settings.gateway_id {
+          Some(gateway_id) if settings.publish_discovery => {
+            gateway::send_gateway_messages(
+              &self.publisher,
+              gateway_id,
+              &settings.availability_topic,
+              &settings.discovery_prefix,
+            );
+          }
+          _ => {}
+        }
+      }
+      Ok(rumqttc::Event::Incoming(rumqttc::Incoming::Publish(publish)))
+        if publish.topic == settings.command_topic =>
+      {
+        match Command::parse(&publish.payload) {
+          Some(command) => {
+            if self.command_tx.try_send(command).is_err() {
+              warn!("Dropping command {:?}, too many are queued", command);
+            }
+          }
+          None => warn!("Ignoring unknown command {:?}", publish.payload),
+        }
+      }
+      Ok(rumqttc::Event::Incoming(rumqttc::Incoming::Publish(publish)))
+        if publish.topic == settings.snapshot_command_topic =>
+      {
+        if self.command_tx.try_send(Command::Snapshot).is_err() {
+          warn!("Dropping snapshot request, too many commands are queued");
+        }
       }
       Ok(rumqttc::Event::Outgoing(rumqttc::Outgoing::Disconnect)) => {
         warn!("Disconnected, retry happening...");
+        if self.connected {
+          self.connected = false;
+          health.mqtt_connected.store(false, Ordering::Relaxed);
+          webhook.notify(
+            "mqtt_disconnected",
+            settings.broker_host.clone().unwrap_or_default(),
+          );
+        }
       }
       Ok(msg) => {
         debug!("Event = {msg:?}");
       }
       Err(e) => {
-        error!("Error = {}", e);
-        error!("Terminating...");
-        break;
+        let detail = format!(
+          "{}: {}",
+          settings.broker_host.as_deref().unwrap_or_default(),
+          e
+        );
+        if self.connected {
+          self.connected = false;
+          health.mqtt_connected.store(false, Ordering::Relaxed);
+          webhook.notify("mqtt_disconnected", detail.clone());
+        }
+
+        // Polling again reconnects. Under an orchestrator it can be better to exit and let it
+        // decide, so the attempts can be capped
+        self.failed_attempts += 1;
+        if let Some(max_attempts) = settings.max_reconnect_attempts {
+          if self.failed_attempts > max_attempts {
+            webhook.notify_and_wait("mqtt_gave_up", detail).await;
+            return Err(Error::Mqtt(format!(
+              "{}, giving up after {} failed reconnect attempts",
+              e, max_attempts
+            )));
+          }
+        }
+        error!(
+          "Error = {}, reconnecting in {}s",
+          e,
+          RECONNECT_DELAY.as_secs()
+        );
+        self.retry_at = Some(tokio::time::Instant::now() + RECONNECT_DELAY);
       }
     }
+    Ok(())
+  }
+
+  // Reports brood-flow offline and disconnects cleanly. The broker only publishes our last will
+  // when the connection drops, so without the message HA would keep showing the sensors available
+  async fn disconnect(mut self, settings: &Configuration) {
+    if !self.connected {
+      return;
+    }
+    let offline = gateway::availability_message(
+      &settings.availability_topic,
+      settings.availability_qos.qos(),
+      settings.availability_retain,
+      false,
+    );
+    let sent = self
+      .client
+      .publish(
+        offline.topic,
+        offline.qos,
+        offline.retain,
+        offline.message.to_vec(),
+      )
+      .await;
+    if sent.is_ok() && self.client.disconnect().await.is_ok() {
+      // Publishes only go out as the eventloop is polled
+      while let Ok(Ok(event)) =
+        tokio::time::timeout(DISCONNECT_TIMEOUT, self.eventloop.poll()).await
+      {
+        if let rumqttc::Event::Outgoing(rumqttc::Outgoing::Disconnect) = event {
+          return;
+        }
+      }
+    }
+    warn!("Couldn't disconnect from the broker cleanly");
+  }
+}
+
+// The next MQTT event, never without a connection
+#[cfg(feature = "mqtt")]
+async fn poll_mqtt(mqtt: &mut Option<Mqtt>) -> MqttEvent {
+  match mqtt {
+    Some(mqtt) => mqtt.poll().await,
+    None => std::future::pending().await,
+  }
+}
+
+#[cfg(feature = "mqtt")]
+async fn handle_mqtt(
+  mqtt: &mut Option<Mqtt>,
+  event: MqttEvent,
+  webhook: &Webhook,
+  health: &Health,
+  settings: &Configuration,
+) -> Result<(), Error> {
+  match mqtt {
+    Some(mqtt) => mqtt.handle(event, webhook, health, settings).await,
+    None => Ok(()),
   }
-  Ok(())
 }
@@ -0,0 +1,227 @@
+use crate::brood_flow_config::Configuration;
+use crate::broodminder_device::{BroodminderDevice, DiscoveryEntry, Reading};
+use crate::cadence;
+use crate::commands::Command;
+use crate::device_names::UNKNOWN_DEVICE_ID;
+use crate::publisher::Publisher;
+use crate::topic_history::TopicHistory;
+use chrono::prelude::Utc;
+use json::JsonValue;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::broadcast::Receiver;
+use tokio::sync::mpsc;
+
+// Publishes readings to Home Assistant over MQTT. This is one subscriber of the readings broadcast,
+// and keeps its own copy of each device so the per-device rate limiting state stays with it
+pub fn start(
+  mut readings: Receiver<Reading>,
+  mut commands: mpsc::Receiver<Command>,
+  publisher: Publisher,
+  settings: Arc<Configuration>,
+) {
+  // Home Assistant may still be starting (and not yet subscribed to discovery topics) when we launch,
+  // so config messages are held back until this delay has passed
+  let started_at = Instant::now();
+  let startup_delay = Duration::from_secs(settings.startup_delay_secs);
+
+  // Cache of discovered devices, as we want to store when the last message was sent per device
+  let mut devices: HashMap<String, BroodminderDevice> = HashMap::new();
+  let mut history = settings.state_file.as_deref().map(TopicHistory::load);
+  let cadence_ms = settings.fixed_cadence_secs.map(|secs| secs as i64 * 1000);
+  let mut last_tick = 0;
+  // Republishes even the devices that haven't been heard lately, in case the broker dropped their
+  // retained config. The first round is a full period after launch, each device's first reading
+  // has sent its config by then
+  let mut republish =
+    (settings.publish_discovery && settings.config_republish_secs > 0).then(|| {
+      let period = Duration::from_secs(settings.config_republish_secs);
+      tokio::time::interval_at(tokio::time::Instant::now() + period, period)
+    });
+
+  tokio::task::spawn(async move {
+    loop {
+      let reading = tokio::select! {
+        reading = readings.recv() => match reading {
+          Ok(reading) => reading,
+          Err(RecvError::Lagged(missed)) => {
+            warn!("MQTT publishing fell behind, skipped {} readings", missed);
+            continue;
+          }
+          Err(RecvError::Closed) => break,
+        },
+        Some(command) = commands.recv() => {
+          info!("Received command {:?}", command);
+          match command {
+            Command::ResendConfig => {
+              if settings.publish_discovery {
+                republish_config(&mut devices, &publisher, &settings);
+              } else {
+                devices.values_mut().for_each(BroodminderDevice::request_config);
+              }
+            }
+            Command::Snapshot => publish_snapshot(&devices, &publisher, &settings),
+            Command::Reconnected => {
+              if let Some(secs) = settings.reconnect_config_spread_secs {
+                stagger_config(&mut devices, secs, Utc::now().timestamp_millis());
+              }
+            }
+          }
+          continue;
+        }
+        _ = cadence::tick(&mut republish), if started_at.elapsed() >= startup_delay => {
+          info!("Republishing the config of {} devices (config_republish_secs)", devices.len());
+          republish_config(&mut devices, &publisher, &settings);
+          continue;
+        }
+        // With fixed_cadence_secs state is only published on the timer, whenever the readings came
+        tick = cadence::next_tick(cadence_ms, last_tick) => {
+          last_tick = tick;
+          let ambient_c = ambient_temperature(&devices, &settings, tick);
+          for device in devices.values_mut() {
+            if !device.is_stale(&settings, tick) {
+              device.set_ambient_temperature(&settings, ambient_c);
+              device.send_state_message(&publisher, &settings, tick);
+            }
+          }
+          continue;
+        }
+      };
+
+      let now = Utc::now().timestamp_millis();
+      let ambient_c = ambient_temperature(&devices, &settings, now);
+      let device = devices
+        .entry(reading.device.address.clone())
+        .and_modify(|device| device.refresh_from(&reading.device))
+        .or_insert_with(|| reading.device.clone());
+
+      if let Some(history) = history.as_mut() {
+        if settings.publish_discovery && device.device_id != UNKNOWN_DEVICE_ID {
+          let entries = device.discovery_entries(&settings);
+          let state_topic = device.state_topic(&settings);
+          if let Some(previous) = history.record(&device.address, &state_topic, &entries) {
+            migrate(device, &previous, &entries, &publisher, &settings);
+          }
+        }
+      }
+
+      // Send our config and state messages (these functions already handle rate limiting)
+      // Users managing their HA entities by hand can opt out of discovery entirely
+      if settings.publish_discovery && started_at.elapsed() >= startup_delay {
+        device.send_config_messages(&publisher, &settings, now);
+      }
+      if cadence_ms.is_none() {
+        device.set_ambient_temperature(&settings, ambient_c);
+        device.send_state_message(&publisher, &settings, now);
+      }
+    }
+  });
+}
+
+// The ambient_device_id device's latest temperature, None if it isn't set, hasn't been heard or
+// has gone quiet, so no delta is published from a stale reading
+fn ambient_temperature(
+  devices: &HashMap<String, BroodminderDevice>,
+  settings: &Configuration,
+  now: i64,
+) -> Option<f32> {
+  settings.ambient_device_id.as_ref()?;
+  devices
+    .values()
+    .find(|device| device.is_ambient(settings) && !device.is_stale(settings, now))
+    .map(|device| device.realtime_temperature_c + device.calibration.temperature_offset_c as f32)
+}
+
+// Sends every device's config straight away, however recently it last went out
+fn republish_config(
+  devices: &mut HashMap<String, BroodminderDevice>,
+  publisher: &Publisher,
+  settings: &Configuration,
+) {
+  let now = Utc::now().timestamp_millis();
+  for device in devices.values_mut() {
+    device.request_config();
+    device.send_config_messages(publisher, settings, now);
+  }
+}
+
+// Publishes every device seen so far as one JSON document to snapshot_topic, keyed by device id,
+// for a dashboard that connects late and wants the current readings without waiting for them
+fn publish_snapshot(
+  devices: &HashMap<String, BroodminderDevice>,
+  publisher: &Publisher,
+  settings: &Configuration,
+) {
+  let mut snapshot = JsonValue::new_object();
+  for device in devices.values() {
+    if device.device_id != UNKNOWN_DEVICE_ID {
+      snapshot[device.device_id.as_str()] = device.snapshot(settings);
+    }
+  }
+  info!(
+    "Publishing a snapshot of {} devices to {}",
+    snapshot.len(),
+    settings.snapshot_topic
+  );
+  publisher.publish(
+    settings.snapshot_topic.clone(),
+    settings.snapshot_qos.qos(),
+    settings.snapshot_retain,
+    snapshot.dump(),
+    "snapshot",
+  );
+}
+
+// Spreads the devices' config republish evenly over `spread_secs`, so a reconnect on a large
+// apiary doesn't send every config at once. Each one goes out with the device's next reading
+// after its slot
+fn stagger_config(devices: &mut HashMap<String, BroodminderDevice>, spread_secs: u64, now: i64) {
+  let spread_ms = spread_secs as i64 * 1000;
+  let count = devices.len() as i64;
+  for (index, device) in devices.values_mut().enumerate() {
+    device.request_config_after(now, spread_ms * index as i64 / count);
+  }
+}
+
+// Moves a device whose topics changed since the last run: its old entities are deleted and the
+// new ones published straight away. HA can't carry history over to the new entities by itself, so
+// each old and new unique_id is logged for merging them by hand
+fn migrate(
+  device: &mut BroodminderDevice,
+  previous: &[DiscoveryEntry],
+  current: &[DiscoveryEntry],
+  publisher: &Publisher,
+  settings: &Configuration,
+) {
+  for old in previous {
+    match current.iter().find(|new| new.sensor_id == old.sensor_id) {
+      Some(new) => warn!(
+        "{} {} moved from {} to {}, entity {} is now {}",
+        device.device_id,
+        old.sensor_id,
+        old.config_topic,
+        new.config_topic,
+        old.unique_id,
+        new.unique_id
+      ),
+      None => warn!(
+        "{} {} is no longer published, removing entity {}",
+        device.device_id, old.sensor_id, old.unique_id
+      ),
+    }
+  }
+
+  let stale: Vec<String> = previous
+    .iter()
+    .filter(|old| {
+      current
+        .iter()
+        .all(|new| new.config_topic != old.config_topic)
+    })
+    .map(|old| old.config_topic.clone())
+    .collect();
+  device.send_delete_messages(publisher, settings, &stale);
+  device.request_config();
+}
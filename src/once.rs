@@ -0,0 +1,77 @@
+// --once runs brood-flow as a batch job, e.g. from cron on a gateway that isn't always on: it
+// publishes as usual until every configured device has been heard, then exits
+use crate::brood_flow_config::DeviceConfiguration;
+use crate::broodminder_device::BroodminderDevice;
+use std::collections::HashMap;
+
+// The configured devices not heard yet
+#[derive(Debug)]
+pub struct Once {
+  pending: Vec<String>,
+}
+
+impl Once {
+  // Tracks every device in the configuration with an id. With none there's nothing to wait for,
+  // and the run lasts until the timeout
+  pub fn new(devices: &[DeviceConfiguration]) -> Once {
+    Once {
+      pending: devices
+        .iter()
+        .filter_map(|device| device.id.clone())
+        .collect(),
+    }
+  }
+
+  pub fn tracks_devices(&self) -> bool {
+    !self.pending.is_empty()
+  }
+
+  // Crosses off the configured devices the decoder has taken a reading from, true once the last
+  // of them is
+  pub fn heard(&mut self, devices: &HashMap<String, BroodminderDevice>) -> bool {
+    if self.pending.is_empty() {
+      return false;
+    }
+    self.pending.retain(|id| {
+      !devices
+        .values()
+        .any(|device| &device.local_name == id || &device.device_id == id)
+    });
+    self.pending.is_empty()
+  }
+
+  // The configured devices still to be heard, for the log at the timeout
+  pub fn pending(&self) -> &[String] {
+    &self.pending
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn finishes_once_every_configured_device_is_heard() {
+    let settings = crate::brood_flow_config::parse(
+      "devices:\n  - id: \"47:01:01\"\n  - id: \"57:01:02\"\n  - name: \"No id\"",
+    )
+    .unwrap();
+    let mut once = Once::new(&settings.devices);
+    assert!(once.tracks_devices());
+
+    let mut devices = HashMap::new();
+    let mut device = BroodminderDevice::default();
+    device.local_name = "47:01:01".to_string();
+    devices.insert("5E:00:00:00:00:01".to_string(), device.clone());
+    assert!(!once.heard(&devices));
+    assert_eq!(once.pending(), ["57:01:02"]);
+
+    device.local_name = "57:01:02".to_string();
+    devices.insert("5E:00:00:00:00:02".to_string(), device);
+    assert!(once.heard(&devices));
+    // Only reported the once
+    assert!(!once.heard(&devices));
+
+    assert!(!Once::new(&[]).tracks_devices());
+  }
+}
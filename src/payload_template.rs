@@ -0,0 +1,161 @@
+// state_payload_template lets consumers other than Home Assistant choose the structure of the state
+// JSON. It maps each output key to a template of {placeholder}s, e.g.
+//   hive: "{device_id}"
+//   readings.temp: "{temperature_c}"
+// gives {"hive": "47:01:01", "readings": {"temp": 24.5}}. A template that is a single reading
+// placeholder keeps the number, the key is left out while the device doesn't have that reading.
+// Anything else is rendered as a string. Dots in keys nest objects
+use crate::broodminder_device::StateReading;
+use crate::topics;
+use json::JsonValue;
+use std::collections::BTreeMap;
+
+// Placeholders besides the StateReading keys
+const EXTRA_PLACEHOLDERS: [&str; 2] = ["device_id", "timestamp"];
+
+// The readings' keys, and the ids of the sensors configured under models
+fn placeholders<'a>(custom_ids: &[&'a str]) -> Vec<&'a str> {
+  let mut placeholders: Vec<&str> = StateReading::default()
+    .fields()
+    .iter()
+    .map(|(key, _)| *key)
+    .collect();
+  placeholders.extend(custom_ids);
+  placeholders.extend(EXTRA_PLACEHOLDERS);
+  placeholders
+}
+
+// Checks every key and placeholder, so a broken template fails at startup
+pub fn validate(template: &BTreeMap<String, String>, custom_ids: &[&str]) -> Result<(), String> {
+  if template.is_empty() {
+    return Err("state_payload_template must have at least one key".to_string());
+  }
+  let allowed = placeholders(custom_ids);
+  for (key, value) in template {
+    if key.split('.').any(str::is_empty) {
+      return Err(format!(
+        "state_payload_template has an invalid key: {:?}",
+        key
+      ));
+    }
+    // A key can't be both a value and an object of other keys
+    if let Some(parent) = template
+      .keys()
+      .find(|other| key.starts_with(&format!("{}.", other)))
+    {
+      return Err(format!(
+        "state_payload_template key {} is inside {}, which already has a value",
+        key, parent
+      ));
+    }
+    topics::validate(&format!("state_payload_template.{}", key), value, &allowed)?;
+  }
+  Ok(())
+}
+
+// Builds the state message for one reading, `now` is the millisecond epoch time
+pub fn render(
+  template: &BTreeMap<String, String>,
+  reading: &StateReading,
+  device_id: &str,
+  now: i64,
+) -> JsonValue {
+  let mut values: Vec<(&str, Option<JsonValue>)> = reading
+    .fields()
+    .iter()
+    .map(|(key, value)| (*key, value.map(JsonValue::from)))
+    .collect();
+  values.extend(
+    reading
+      .custom
+      .iter()
+      .map(|(key, value)| (*key, Some(JsonValue::from(*value)))),
+  );
+  values.push(("device_id", Some(device_id.into())));
+  values.push(("timestamp", Some(now.into())));
+
+  let mut payload = JsonValue::new_object();
+  for (key, value_template) in template {
+    let value = match values
+      .iter()
+      .find(|(name, _)| value_template.trim() == format!("{{{}}}", name))
+    {
+      Some((_, value)) => value.clone(),
+      None => {
+        let mut rendered = value_template.clone();
+        for (name, value) in &values {
+          let value = match value {
+            Some(JsonValue::String(value)) => value.clone(),
+            Some(value) => value.dump(),
+            None => String::new(),
+          };
+          rendered = rendered.replace(&format!("{{{}}}", name), &value);
+        }
+        Some(rendered.into())
+      }
+    };
+
+    if let Some(value) = value {
+      let mut parts: Vec<&str> = key.split('.').collect();
+      let last = parts.pop().unwrap();
+      let mut object = &mut payload;
+      for part in parts {
+        if !object.has_key(part) {
+          object[part] = JsonValue::new_object();
+        }
+        object = &mut object[part];
+      }
+      object[last] = value;
+    }
+  }
+  payload
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn template(yaml: &[(&str, &str)]) -> BTreeMap<String, String> {
+    yaml
+      .iter()
+      .map(|(key, value)| (key.to_string(), value.to_string()))
+      .collect()
+  }
+
+  #[test]
+  fn render_builds_nested_objects() {
+    let template = template(&[
+      ("hive", "{device_id}"),
+      ("readings.temp", "{temperature_c}"),
+      ("readings.weight", "{weight_kg}"),
+      ("label", "{temperature_c} C"),
+      ("at", "{timestamp}"),
+    ]);
+    let reading = StateReading {
+      temperature_c: Some(24.5),
+      ..Default::default()
+    };
+
+    assert_eq!(
+      render(&template, &reading, "47:01:01", 1_700_000_000_000).dump(),
+      r#"{"at":1700000000000,"hive":"47:01:01","label":"24.5 C","readings":{"temp":24.5}}"#
+    );
+  }
+
+  #[test]
+  fn validate_rejects_bad_templates() {
+    assert!(validate(&template(&[("temp", "{temperature_c}")]), &[]).is_ok());
+    assert!(validate(&template(&[("temp", "{temprature_c}")]), &[]).is_err());
+    assert!(validate(&template(&[("co2", "{co2}")]), &["co2"]).is_ok());
+    assert!(validate(&template(&[("readings..temp", "{temperature_c}")]), &[]).is_err());
+    assert!(validate(
+      &template(&[
+        ("readings", "{device_id}"),
+        ("readings.temp", "{temperature_c}")
+      ]),
+      &[]
+    )
+    .is_err());
+    assert!(validate(&BTreeMap::new(), &[]).is_err());
+  }
+}
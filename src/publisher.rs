@@ -0,0 +1,224 @@
+use crate::brood_flow_config::RateLimitOverflow;
+use rumqttc::{AsyncClient, QoS};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::Semaphore;
+
+// Token bucket shared by every publish, refilled continuously at `rate` tokens per second and
+// holding at most `capacity` tokens so short bursts are allowed but the average rate is capped
+#[derive(Debug)]
+struct TokenBucket {
+  rate: f64,
+  capacity: f64,
+  tokens: f64,
+  last_refill: Instant,
+}
+
+impl TokenBucket {
+  fn new(rate: f64, now: Instant) -> Self {
+    let capacity = rate.max(1.0);
+    Self {
+      rate,
+      capacity,
+      tokens: capacity,
+      last_refill: now,
+    }
+  }
+
+  // Takes a token if one is available, otherwise returns how long until one will be
+  fn try_take(&mut self, now: Instant) -> Result<(), Duration> {
+    let elapsed = now
+      .saturating_duration_since(self.last_refill)
+      .as_secs_f64();
+    self.tokens = (self.tokens + elapsed * self.rate).min(self.capacity);
+    self.last_refill = now;
+
+    if self.tokens >= 1.0 {
+      self.tokens -= 1.0;
+      Ok(())
+    } else {
+      Err(Duration::from_secs_f64((1.0 - self.tokens) / self.rate))
+    }
+  }
+}
+
+// Kinds of message the device's next one replaces, so dropping one over max_concurrent_publishes
+// loses nothing for long. Others (config, availability) would leave HA wrong until they're resent
+const SUPERSEDED_KINDS: [&str; 4] = ["state", "attributes", "summary", "snapshot"];
+
+// Wraps the MQTT client so every outbound message goes through the same global rate limit
+#[derive(Clone)]
+pub struct Publisher {
+  client: AsyncClient,
+  limiter: Option<Arc<Mutex<TokenBucket>>>,
+  overflow: RateLimitOverflow,
+  sent: Arc<AtomicU64>, // Messages handed to the client so far, for the heartbeat
+  in_flight: Option<Arc<Semaphore>>, // One permit per publish task, with max_concurrent_publishes
+}
+
+impl Publisher {
+  pub fn new(
+    client: AsyncClient,
+    max_publishes_per_sec: Option<f64>,
+    overflow: RateLimitOverflow,
+    max_concurrent_publishes: Option<usize>,
+  ) -> Self {
+    Self {
+      client,
+      limiter: max_publishes_per_sec
+        .filter(|rate| *rate > 0.0)
+        .map(|rate| Arc::new(Mutex::new(TokenBucket::new(rate, Instant::now())))),
+      overflow,
+      sent: Arc::default(),
+      in_flight: max_concurrent_publishes.map(|max| Arc::new(Semaphore::new(max))),
+    }
+  }
+
+  // Counts every message handed to the MQTT client, across all clones of this publisher
+  pub fn sent_counter(&self) -> Arc<AtomicU64> {
+    self.sent.clone()
+  }
+
+  // Publishes on its own task, so callers never block on the broker or the rate limit.
+  // `kind` is e.g. "state" or "config", for logging. With max_concurrent_publishes a state
+  // message (see SUPERSEDED_KINDS) takes a permit before its task is spawned, and is dropped when
+  // there are none left, so a burst (or a broker that's gone away) can't pile up tasks without
+  // bound. Any other message waits for a permit on its task instead, there are only ever as many of
+  // those as there are entities
+  pub fn publish(
+    &self,
+    topic: String,
+    qos: QoS,
+    retain: bool,
+    payload: impl Into<Vec<u8>>,
+    kind: &'static str,
+  ) {
+    let permit = match &self.in_flight {
+      Some(in_flight) if SUPERSEDED_KINDS.contains(&kind) => {
+        match in_flight.clone().try_acquire_owned() {
+          Ok(permit) => Some(permit),
+          Err(_) => {
+            warn!(
+              "Too many publishes in flight, dropping {} message to {}",
+              kind, topic
+            );
+            return;
+          }
+        }
+      }
+      _ => None,
+    };
+    let payload = payload.into();
+    let publisher = self.clone();
+    tokio::task::spawn(async move {
+      // Released when the publish is done. The semaphore is never closed, so acquiring can't fail
+      let _permit = match (permit, &publisher.in_flight) {
+        (None, Some(in_flight)) => in_flight.clone().acquire_owned().await.ok(),
+        (permit, _) => permit,
+      };
+      if !publisher.acquire().await {
+        warn!(
+          "Publish rate limit exceeded, dropping {} message to {}",
+          kind, topic
+        );
+        return;
+      }
+
+      match publisher.client.publish(topic, qos, retain, payload).await {
+        Err(error) => info!("Error: {:?}", error),
+        Ok(_) => {
+          publisher.sent.fetch_add(1, Ordering::Relaxed);
+          info!("Sent {}!", kind)
+        }
+      }
+    });
+  }
+
+  // Subscriptions aren't rate limited, there are only ever a handful of them
+  pub fn subscribe(&self, topic: String, qos: QoS) {
+    let client = self.client.clone();
+    tokio::task::spawn(async move {
+      match client.subscribe(&topic, qos).await {
+        Err(error) => info!("Error: {:?}", error),
+        Ok(_) => info!("Subscribed to {}!", topic),
+      }
+    });
+  }
+
+  // Waits for room in the rate limiter, returns false if the message should be dropped instead
+  async fn acquire(&self) -> bool {
+    let limiter = match &self.limiter {
+      Some(limiter) => limiter,
+      None => return true,
+    };
+
+    loop {
+      let result = limiter.lock().unwrap().try_take(Instant::now());
+      match result {
+        Ok(()) => return true,
+        Err(_) if self.overflow == RateLimitOverflow::Drop => return false,
+        Err(wait) => tokio::time::sleep(wait).await,
+      }
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn token_bucket_allows_burst_then_limits() {
+    let start = Instant::now();
+    let mut bucket = TokenBucket::new(2.0, start);
+
+    assert!(bucket.try_take(start).is_ok());
+    assert!(bucket.try_take(start).is_ok());
+    let wait = bucket.try_take(start).unwrap_err();
+    assert_eq!(wait, Duration::from_millis(500));
+
+    // Half a second later one more token has been refilled
+    let later = start + Duration::from_millis(500);
+    assert!(bucket.try_take(later).is_ok());
+    assert!(bucket.try_take(later).is_err());
+  }
+
+  #[test]
+  fn token_bucket_never_exceeds_capacity() {
+    let start = Instant::now();
+    let mut bucket = TokenBucket::new(1.0, start);
+
+    let much_later = start + Duration::from_secs(60);
+    assert!(bucket.try_take(much_later).is_ok());
+    assert!(bucket.try_take(much_later).is_err());
+  }
+
+  #[tokio::test]
+  async fn state_over_the_concurrency_cap_is_dropped_and_config_waits() {
+    // With room for one request and nothing polling the event loop, the first publish fills the
+    // queue and the second is stuck holding the only permit
+    let options = rumqttc::MqttOptions::new("test", "localhost", 1883);
+    let (client, eventloop) = AsyncClient::new(options, 1);
+    let publisher = Publisher::new(client, None, RateLimitOverflow::Wait, Some(1));
+    for (topic, kind) in [
+      ("first", "state"),
+      ("second", "state"),
+      ("third", "state"),
+      ("fourth", "config"),
+      ("fifth", "availability"),
+    ] {
+      publisher.publish(topic.to_string(), QoS::AtMostOnce, false, "", kind);
+      tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+
+    let mut topics = vec![];
+    let timeout = Duration::from_millis(50);
+    while let Ok(Ok(request)) = tokio::time::timeout(timeout, eventloop.requests_rx.recv()).await {
+      if let rumqttc::Request::Publish(publish) = request {
+        topics.push(publish.topic);
+      }
+    }
+    assert_eq!(topics, ["first", "second", "fourth", "fifth"]);
+  }
+}
@@ -0,0 +1,122 @@
+use crate::brood_flow_config::Configuration;
+use crate::broodminder_device::{Reading, StateReading};
+use crate::device_names::UNKNOWN_DEVICE_ID;
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::broadcast::Receiver;
+
+// Gauges for the latest reading of every device, in the Prometheus text format, e.g.
+// broodminder_temperature_c{device="47:01:01"} 24.5
+fn render(readings: &BTreeMap<String, StateReading>) -> String {
+  let mut gauges: BTreeMap<&str, Vec<String>> = BTreeMap::new();
+  for (device_id, reading) in readings {
+    let label = device_id.replace('\\', "\\\\").replace('"', "\\\"");
+    for (key, value) in reading.readings() {
+      gauges.entry(key).or_default().push(format!(
+        "broodminder_{}{{device=\"{}\"}} {}",
+        key, label, value
+      ));
+    }
+  }
+
+  let mut body = String::new();
+  for (key, samples) in gauges {
+    body.push_str(&format!("# TYPE broodminder_{} gauge\n", key));
+    for sample in samples {
+      body.push_str(&sample);
+      body.push('\n');
+    }
+  }
+  body
+}
+
+// Pushes the latest readings to a Prometheus Pushgateway every push_interval_secs, for gateways
+// Prometheus can't reach to scrape (e.g. behind NAT). Each push replaces the previous one for this
+// gateway, grouped by client_id
+pub fn start(url: &str, mut readings: Receiver<Reading>, settings: Arc<Configuration>) {
+  let url = format!(
+    "{}/metrics/job/brood_flow/instance/{}",
+    url.trim_end_matches('/'),
+    settings.client_id
+  );
+  info!("Pushing metrics to {}", url);
+
+  let mut latest: BTreeMap<String, StateReading> = BTreeMap::new();
+  let mut interval = tokio::time::interval(Duration::from_secs(settings.push_interval_secs));
+
+  tokio::task::spawn(async move {
+    loop {
+      tokio::select! {
+        reading = readings.recv() => match reading {
+          Ok(reading) => {
+            if reading.device.device_id != UNKNOWN_DEVICE_ID {
+              latest.insert(
+                reading.device.device_id.clone(),
+                reading.device.state_reading(settings.decimal_places),
+              );
+            }
+          }
+          Err(RecvError::Lagged(_)) => {}
+          Err(RecvError::Closed) => break,
+        },
+        _ = interval.tick() => {
+          if latest.is_empty() {
+            continue;
+          }
+          let body = render(&latest);
+          let url = url.clone();
+          // ureq blocks, so keep it off the runtime's workers
+          let result = tokio::task::spawn_blocking(move || {
+            ureq::put(&url)
+              .timeout(Duration::from_secs(10))
+              .set("Content-Type", "text/plain; version=0.0.4")
+              .send_string(&body)
+              .map(|_| ())
+              .map_err(|error| error.to_string())
+          })
+          .await
+          .unwrap_or_else(|error| Err(error.to_string()));
+          if let Err(error) = result {
+            warn!("Couldn't push metrics: {}", error);
+          }
+        }
+      }
+    }
+  });
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn render_groups_gauges_by_reading() {
+    let mut readings = BTreeMap::new();
+    readings.insert(
+      "47:01:01".to_string(),
+      StateReading {
+        temperature_c: Some(24.5),
+        weight_lbs: Some(44.1),
+        ..Default::default()
+      },
+    );
+    readings.insert(
+      "47:01:02".to_string(),
+      StateReading {
+        temperature_c: Some(30.0),
+        ..Default::default()
+      },
+    );
+
+    assert_eq!(
+      render(&readings),
+      "# TYPE broodminder_temperature_c gauge\n\
+       broodminder_temperature_c{device=\"47:01:01\"} 24.5\n\
+       broodminder_temperature_c{device=\"47:01:02\"} 30\n\
+       # TYPE broodminder_weight_lbs gauge\n\
+       broodminder_weight_lbs{device=\"47:01:01\"} 44.1\n"
+    );
+  }
+}
@@ -0,0 +1,440 @@
+// The REST API (see rest_port): every device's current reading, and calibration set at runtime.
+// Just enough HTTP/1.1 for curl and fetch(), one request per connection
+use crate::brood_flow_config::Configuration;
+use crate::broodminder_device::{BroodminderDevice, Calibration};
+use crate::error::Error;
+use json::{object, JsonValue};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+// A request is a line, a few headers and a small JSON body, anything bigger is refused
+const MAX_REQUEST_LEN: usize = 16 * 1024;
+// Clients that haven't sent their whole request by then are dropped
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, PartialEq)]
+struct Request {
+  method: String,
+  path: String,
+  body: Vec<u8>,
+}
+
+#[derive(Debug, PartialEq)]
+struct Response {
+  status: u16,
+  body: JsonValue,
+}
+
+impl Response {
+  fn ok(body: JsonValue) -> Response {
+    Response { status: 200, body }
+  }
+
+  fn error(status: u16, message: impl Into<String>) -> Response {
+    Response {
+      status,
+      body: object! { error: message.into() },
+    }
+  }
+
+  fn to_http(&self) -> String {
+    let reason = match self.status {
+      200 => "OK",
+      400 => "Bad Request",
+      404 => "Not Found",
+      405 => "Method Not Allowed",
+      413 => "Payload Too Large",
+      _ => "Error",
+    };
+    let body = self.body.dump();
+    format!(
+      "HTTP/1.1 {} {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+      self.status,
+      reason,
+      body.len(),
+      body
+    )
+  }
+}
+
+// Serves the API on rest_bind:port until brood-flow exits. Not being able to listen (e.g. the port
+// is taken) is an error, rather than quietly running without it
+pub async fn start(
+  port: u16,
+  devices: Arc<Mutex<HashMap<String, BroodminderDevice>>>,
+  settings: Arc<Configuration>,
+) -> Result<(), Error> {
+  let address = format!("{}:{}", settings.rest_bind, port);
+  let listener = TcpListener::bind(&address).await.map_err(|error| {
+    std::io::Error::new(
+      error.kind(),
+      format!("Couldn't listen on {} for the REST API: {}", address, error),
+    )
+  })?;
+  info!("Serving the REST API on http://{}", address);
+
+  tokio::task::spawn(async move {
+    loop {
+      match listener.accept().await {
+        Ok((stream, _)) => {
+          tokio::task::spawn(serve(stream, devices.clone(), settings.clone()));
+        }
+        Err(error) => {
+          // e.g. out of file descriptors, which won't clear up straight away
+          warn!("Couldn't accept a REST API connection: {}", error);
+          tokio::time::sleep(Duration::from_secs(1)).await;
+        }
+      }
+    }
+  });
+  Ok(())
+}
+
+async fn serve(
+  mut stream: TcpStream,
+  devices: Arc<Mutex<HashMap<String, BroodminderDevice>>>,
+  settings: Arc<Configuration>,
+) {
+  let response = match tokio::time::timeout(REQUEST_TIMEOUT, read_request(&mut stream)).await {
+    Ok(Ok(request)) => {
+      debug!("REST API: {} {}", request.method, request.path);
+      respond(&request, &mut devices.lock().unwrap(), &settings)
+    }
+    Ok(Err(response)) => response,
+    Err(_) => return,
+  };
+  if let Err(error) = stream.write_all(response.to_http().as_bytes()).await {
+    debug!("Couldn't send a REST API response: {}", error);
+  }
+}
+
+// Reads the request line and headers, then as much body as Content-Length says follows
+async fn read_request(stream: &mut TcpStream) -> Result<Request, Response> {
+  let too_large = || Response::error(413, "The request is too large");
+  let cut_short = || Response::error(400, "The request was cut short");
+  let mut buffer = Vec::new();
+  let mut chunk = [0u8; 1024];
+  let head_len = loop {
+    if let Some(end) = buffer.windows(4).position(|window| window == b"\r\n\r\n") {
+      break end + 4;
+    }
+    if buffer.len() > MAX_REQUEST_LEN {
+      return Err(too_large());
+    }
+    match stream.read(&mut chunk).await {
+      Ok(0) | Err(_) => return Err(cut_short()),
+      Ok(read) => buffer.extend_from_slice(&chunk[..read]),
+    }
+  };
+
+  let head = String::from_utf8_lossy(&buffer[..head_len]).to_string();
+  let (method, path, content_length) =
+    parse_head(&head).ok_or_else(|| Response::error(400, "Malformed request"))?;
+  if content_length
+    .checked_add(head_len)
+    .is_none_or(|len| len > MAX_REQUEST_LEN)
+  {
+    return Err(too_large());
+  }
+  let mut body = buffer.split_off(head_len);
+  while body.len() < content_length {
+    match stream.read(&mut chunk).await {
+      Ok(0) | Err(_) => return Err(cut_short()),
+      Ok(read) => body.extend_from_slice(&chunk[..read]),
+    }
+  }
+  body.truncate(content_length);
+  Ok(Request { method, path, body })
+}
+
+// The method, path and Content-Length (0 without one) of e.g. "GET /devices HTTP/1.1\r\n..."
+fn parse_head(head: &str) -> Option<(String, String, usize)> {
+  let mut lines = head.split("\r\n");
+  let mut request_line = lines.next()?.split(' ');
+  let (method, path) = (request_line.next()?, request_line.next()?);
+  let mut content_length = 0;
+  for (name, value) in lines.filter_map(|line| line.split_once(':')) {
+    if name.trim().eq_ignore_ascii_case("content-length") {
+      content_length = value.trim().parse().ok()?;
+    }
+  }
+  Some((method.to_string(), path.to_string(), content_length))
+}
+
+fn respond(
+  request: &Request,
+  devices: &mut HashMap<String, BroodminderDevice>,
+  settings: &Configuration,
+) -> Response {
+  let path = request.path.split('?').next().unwrap_or_default();
+  let segments: Vec<String> = path
+    .trim_matches('/')
+    .split('/')
+    .map(percent_decode)
+    .collect();
+  let segments: Vec<&str> = segments.iter().map(String::as_str).collect();
+  match (request.method.as_str(), segments.as_slice()) {
+    ("GET", ["devices"]) => {
+      let mut sorted: Vec<&BroodminderDevice> = devices.values().collect();
+      sorted.sort_by(|a, b| a.device_id.cmp(&b.device_id));
+      Response::ok(JsonValue::Array(
+        sorted
+          .into_iter()
+          .map(|device| device_json(device, settings))
+          .collect(),
+      ))
+    }
+    ("GET", ["devices", id]) => match find(devices, id) {
+      Some(device) => Response::ok(device_json(device, settings)),
+      None => Response::error(404, format!("No device {} has been heard", id)),
+    },
+    ("POST", ["devices", id, "calibration"]) => match find(devices, id) {
+      Some(device) => match updated_calibration(&device.calibration, &request.body) {
+        Ok(calibration) => {
+          info!(
+            "Calibration of {} set to {:?}",
+            device.device_id, calibration
+          );
+          device.calibration = calibration;
+          Response::ok(device_json(device, settings))
+        }
+        Err(message) => Response::error(400, message),
+      },
+      None => Response::error(404, format!("No device {} has been heard", id)),
+    },
+    (_, ["devices"]) | (_, ["devices", _]) | (_, ["devices", _, "calibration"]) => Response::error(
+      405,
+      format!("{} isn't supported on {}", request.method, path),
+    ),
+    _ => Response::error(404, format!("Nothing at {}", path)),
+  }
+}
+
+// A device by its device id or MAC address
+fn find<'a>(
+  devices: &'a mut HashMap<String, BroodminderDevice>,
+  id: &str,
+) -> Option<&'a mut BroodminderDevice> {
+  devices
+    .values_mut()
+    .find(|device| device.device_id == id || device.address.eq_ignore_ascii_case(id))
+}
+
+// Its entry in a snapshot, plus its calibration
+fn device_json(device: &BroodminderDevice, settings: &Configuration) -> JsonValue {
+  let mut entry = device.snapshot(settings);
+  entry["calibration"] = object! {
+    temperature_offset_c: device.calibration.temperature_offset_c,
+    weight_offset_kg: device.calibration.weight_offset_kg,
+  };
+  entry
+}
+
+// The calibration with the offsets in body applied, e.g. {"weight_offset_kg": 1.2}. Offsets left
+// out are kept, null resets one
+fn updated_calibration(calibration: &Calibration, body: &[u8]) -> Result<Calibration, String> {
+  let body = std::str::from_utf8(body).map_err(|_| "The body isn't UTF-8".to_string())?;
+  let body = json::parse(body).map_err(|error| format!("The body isn't JSON: {}", error))?;
+  if !body.is_object() {
+    return Err("The body must be a JSON object of offsets".to_string());
+  }
+  let mut updated = calibration.clone();
+  for (key, value) in body.entries() {
+    let offset = match value {
+      JsonValue::Null => None,
+      value => Some(
+        value
+          .as_f64()
+          .ok_or_else(|| format!("{} must be a number or null", key))?,
+      ),
+    };
+    match key {
+      "temperature_offset_c" => updated.temperature_offset_c = offset.unwrap_or_default(),
+      "weight_offset_kg" => updated.weight_offset_kg = offset.unwrap_or_default(),
+      _ => return Err(format!("Unknown offset {}", key)),
+    }
+  }
+  Ok(updated)
+}
+
+// Decodes %XX escapes, e.g. from a device id with spaces. Invalid escapes are left as they are
+fn percent_decode(segment: &str) -> String {
+  let bytes = segment.as_bytes();
+  let mut decoded = Vec::with_capacity(bytes.len());
+  let mut i = 0;
+  while i < bytes.len() {
+    let escaped = bytes
+      .get(i + 1..i + 3)
+      .filter(|_| bytes[i] == b'%')
+      .and_then(|hex| u8::from_str_radix(std::str::from_utf8(hex).ok()?, 16).ok());
+    match escaped {
+      Some(byte) => {
+        decoded.push(byte);
+        i += 3;
+      }
+      None => {
+        decoded.push(bytes[i]);
+        i += 1;
+      }
+    }
+  }
+  String::from_utf8_lossy(&decoded).to_string()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn request(method: &str, path: &str, body: &str) -> Request {
+    Request {
+      method: method.to_string(),
+      path: path.to_string(),
+      body: body.as_bytes().to_vec(),
+    }
+  }
+
+  #[test]
+  fn devices_are_listed_found_and_calibrated() {
+    let settings = crate::brood_flow_config::parse("devices: []").unwrap();
+    let mut devices = HashMap::new();
+    for (address, device_id) in [
+      ("5E:00:00:00:00:02", "57:01:02"),
+      ("5E:00:00:00:00:01", "47:01:01"),
+    ] {
+      let mut device = BroodminderDevice::default();
+      device.device_id = device_id.to_string();
+      device.address = address.to_string();
+      devices.insert(address.to_string(), device);
+    }
+
+    let response = respond(&request("GET", "/devices", ""), &mut devices, &settings);
+    assert_eq!(response.status, 200);
+    assert_eq!(response.body[0]["device_id"], "47:01:01");
+    assert_eq!(response.body[1]["device_id"], "57:01:02");
+
+    let response = respond(
+      &request("GET", "/devices/5e:00:00:00:00:02", ""),
+      &mut devices,
+      &settings,
+    );
+    assert_eq!(response.body["device_id"], "57:01:02");
+    assert_eq!(response.body["calibration"]["weight_offset_kg"], 0.0);
+    assert_eq!(response.body["calibration"]["temperature_offset_c"], 0.0);
+
+    let response = respond(
+      &request(
+        "POST",
+        "/devices/47%3A01%3A01/calibration",
+        r#"{"temperature_offset_c": -0.5}"#,
+      ),
+      &mut devices,
+      &settings,
+    );
+    assert_eq!(response.status, 200);
+    assert_eq!(response.body["calibration"]["temperature_offset_c"], -0.5);
+    let response = respond(
+      &request(
+        "POST",
+        "/devices/47:01:01/calibration",
+        r#"{"temperature_offset_c": null, "weight_offset_kg": 1.5}"#,
+      ),
+      &mut devices,
+      &settings,
+    );
+    assert_eq!(response.status, 200);
+    assert_eq!(
+      devices["5E:00:00:00:00:01"].calibration,
+      Calibration {
+        temperature_offset_c: 0.0,
+        weight_offset_kg: 1.5,
+      }
+    );
+  }
+
+  #[test]
+  fn bad_requests_are_refused() {
+    let settings = crate::brood_flow_config::parse("devices: []").unwrap();
+    let mut device = BroodminderDevice::default();
+    device.device_id = "47:01:01".to_string();
+    let mut devices = HashMap::from([("5E:00:00:00:00:01".to_string(), device)]);
+
+    let cases = [
+      (request("GET", "/devices/57:01:02", ""), 404),
+      (request("GET", "/metrics", ""), 404),
+      (request("DELETE", "/devices/47:01:01", ""), 405),
+      (request("POST", "/devices/47:01:01/calibration", "{"), 400),
+      (request("POST", "/devices/47:01:01/calibration", "[1]"), 400),
+      (
+        request(
+          "POST",
+          "/devices/47:01:01/calibration",
+          r#"{"weight_offset_kg": "1"}"#,
+        ),
+        400,
+      ),
+      (
+        request(
+          "POST",
+          "/devices/47:01:01/calibration",
+          r#"{"pressure_offset": 1}"#,
+        ),
+        400,
+      ),
+    ];
+    for (request, status) in cases {
+      let response = respond(&request, &mut devices, &settings);
+      assert_eq!(
+        response.status, status,
+        "{} {}",
+        request.method, request.path
+      );
+      assert!(response.body["error"].is_string());
+    }
+    assert_eq!(
+      devices["5E:00:00:00:00:01"].calibration,
+      Calibration::default()
+    );
+  }
+
+  #[test]
+  fn request_heads_are_parsed() {
+    assert_eq!(
+      parse_head("POST /devices/47:01:01/calibration HTTP/1.1\r\nHost: gateway\r\ncontent-length: 24\r\n\r\n"),
+      Some((
+        "POST".to_string(),
+        "/devices/47:01:01/calibration".to_string(),
+        24
+      ))
+    );
+    assert_eq!(
+      parse_head("GET /devices HTTP/1.1\r\n\r\n"),
+      Some(("GET".to_string(), "/devices".to_string(), 0))
+    );
+    assert_eq!(parse_head("GET\r\n\r\n"), None);
+    assert_eq!(percent_decode("Hive%201%2"), "Hive 1%2");
+  }
+
+  #[tokio::test]
+  async fn huge_content_lengths_are_too_large() {
+    // e.g. 18446744073709551615, which would overflow when added to the head's length
+    let head = format!(
+      "POST /devices/47:01:01/calibration HTTP/1.1\r\nContent-Length: {}\r\n\r\n",
+      usize::MAX
+    );
+    assert_eq!(parse_head(&head).map(|(_, _, len)| len), Some(usize::MAX));
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let mut client = TcpStream::connect(listener.local_addr().unwrap())
+      .await
+      .unwrap();
+    client.write_all(head.as_bytes()).await.unwrap();
+    let (mut stream, _) = listener.accept().await.unwrap();
+    match read_request(&mut stream).await {
+      Err(response) => assert_eq!(response.status, 413),
+      Ok(_) => panic!("a huge Content-Length was accepted"),
+    }
+  }
+}
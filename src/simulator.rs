@@ -0,0 +1,213 @@
+use crate::ble_scanner::Advertisement;
+use crate::broodminder_device::{ModelInfo, MANUFACTURER_ID, MODELS};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::mpsc::Sender;
+
+// Roughly how often a real Broodminder sensor advertises
+const ADVERTISEMENT_INTERVAL: Duration = Duration::from_secs(5);
+
+const SIMULATED_ADAPTER: &str = "simulator";
+
+// Tiny xorshift generator, the readings only need to look plausible and wander around
+struct Rng(u64);
+
+impl Rng {
+  fn new(seed: u64) -> Rng {
+    Rng(seed | 1) // xorshift gets stuck on 0
+  }
+
+  // A value in [min, max)
+  fn range(&mut self, min: f32, max: f32) -> f32 {
+    self.0 ^= self.0 << 13;
+    self.0 ^= self.0 >> 7;
+    self.0 ^= self.0 << 17;
+    min + (max - min) * ((self.0 >> 40) as f32 / (1u64 << 24) as f32)
+  }
+}
+
+// The readings of one synthetic sensor, which take a small random step every advertisement
+struct SimulatedDevice {
+  info: &'static ModelInfo,
+  address: String,
+  local_name: String,
+  rng: Rng,
+  temperature_c: f32,
+  probe2_c: f32, // Only for models with a second probe
+  weight_kg: f32,
+  pressure_hpa: f32,
+  activity: f32,
+  battery_percent: f32,
+}
+
+impl SimulatedDevice {
+  fn new(index: usize, seed: u64) -> SimulatedDevice {
+    let info = &MODELS[index % MODELS.len()];
+    let mut rng = Rng::new(seed ^ (index as u64).wrapping_mul(0x9E37_79B9_7F4A_7C15));
+    let (high, low) = ((index >> 8) as u8, index as u8);
+
+    SimulatedDevice {
+      info,
+      address: format!("5E:00:00:00:{:02X}:{:02X}", high, low),
+      local_name: format!("{}:{:02x}:{:02x}", info.model, high, low),
+      temperature_c: rng.range(20.0, 35.0),
+      probe2_c: rng.range(15.0, 30.0),
+      weight_kg: rng.range(20.0, 80.0),
+      pressure_hpa: rng.range(980.0, 1030.0),
+      activity: rng.range(0.0, 40.0),
+      battery_percent: rng.range(50.0, 100.0),
+      rng,
+    }
+  }
+
+  fn step(&mut self) {
+    self.temperature_c = (self.temperature_c + self.rng.range(-0.1, 0.1)).clamp(-20.0, 45.0);
+    self.probe2_c = (self.probe2_c + self.rng.range(-0.1, 0.1)).clamp(-20.0, 45.0);
+    self.weight_kg = (self.weight_kg + self.rng.range(-0.05, 0.05)).clamp(0.0, 150.0);
+    self.pressure_hpa = (self.pressure_hpa + self.rng.range(-0.2, 0.2)).clamp(950.0, 1050.0);
+    self.activity = (self.activity + self.rng.range(-2.0, 2.0)).clamp(0.0, 255.0);
+  }
+
+  fn advertisement(&mut self) -> Advertisement {
+    let data = self.payload();
+    Advertisement {
+      adapter: SIMULATED_ADAPTER.to_string(),
+      address: self.address.clone(),
+      local_name: self.local_name.clone(),
+      rssi: Some(self.rng.range(-90.0, -50.0) as i16),
+      manufacturer_lengths: [(MANUFACTURER_ID, data.len())].into_iter().collect(),
+      data,
+    }
+  }
+
+  // Lays the readings out the way BroodminderDevice::update decodes them
+  fn payload(&self) -> Vec<u8> {
+    let mut data = vec![0u8; 25];
+    data[0] = self.info.model;
+    data[1] = 1;
+    data[2] = 1;
+    data[4] = self.battery_percent as u8;
+
+    let (temp_low, temp_high) = encode_temperature(self.temperature_c);
+    data[3] = temp_low;
+    data[9] = temp_high;
+    data[7] = temp_low;
+    data[8] = temp_high;
+
+    if let Some((low, high)) = self.info.second_probe {
+      (data[low], data[high]) = encode_temperature(self.probe2_c);
+    }
+
+    if self.info.weight {
+      let (weight_low, weight_high) = encode_weight(self.weight_kg);
+      data[19] = weight_low;
+      data[20] = weight_high;
+    }
+
+    if self.info.pressure {
+      let pressure = (self.pressure_hpa * 10.0).round() as u16;
+      data[15] = pressure as u8;
+      data[16] = (pressure >> 8) as u8;
+    }
+
+    if let Some(byte) = self.info.activity {
+      data[byte] = self.activity as u8;
+    }
+
+    data
+  }
+}
+
+fn encode_temperature(temperature_c: f32) -> (u8, u8) {
+  let raw = (temperature_c * 100.0 + 5000.0).round() as u16;
+  (raw as u8, (raw >> 8) as u8)
+}
+
+// Inverse of (256 * high + low - 32767) / 100
+fn encode_weight(weight_kg: f32) -> (u8, u8) {
+  let raw = ((weight_kg * 100.0).round() as i32 + 32767) as u16;
+  (raw as u8, (raw >> 8) as u8)
+}
+
+// One advertisement from a synthetic sensor of `model`, None if brood-flow doesn't know the model
+pub fn advertisement_for_model(model: u8) -> Option<Advertisement> {
+  let index = MODELS.iter().position(|info| info.model == model)?;
+  Some(SimulatedDevice::new(index, 42).advertisement())
+}
+
+// Feeds `count` synthetic sensors into the advertisement channel, as if a bluetooth adapter had
+// heard them. Each one advertises on its own task, staggered across the interval like real sensors
+pub fn start(count: usize, advertisements: Sender<Advertisement>) {
+  info!("Simulating {} Broodminder devices", count);
+  let seed = SystemTime::now()
+    .duration_since(UNIX_EPOCH)
+    .map(|elapsed| elapsed.as_nanos() as u64)
+    .unwrap_or_default();
+
+  for index in 0..count {
+    let advertisements = advertisements.clone();
+    let mut device = SimulatedDevice::new(index, seed);
+    let offset = ADVERTISEMENT_INTERVAL.mul_f64(index as f64 / count as f64);
+
+    tokio::task::spawn(async move {
+      tokio::time::sleep(offset).await;
+      let mut interval = tokio::time::interval(ADVERTISEMENT_INTERVAL);
+      loop {
+        interval.tick().await;
+        device.step();
+        if advertisements.send(device.advertisement()).await.is_err() {
+          break;
+        }
+      }
+    });
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::broodminder_device::BroodminderDevice;
+
+  #[test]
+  fn payload_decodes_to_the_simulated_readings() {
+    for index in 0..MODELS.len() {
+      let mut simulated = SimulatedDevice::new(index, 42);
+      simulated.step();
+      let device =
+        BroodminderDevice::build_broodminder_device(&simulated.advertisement().data).unwrap();
+
+      assert_eq!(device.model, simulated.info.model);
+      assert!((device.temperature_c - simulated.temperature_c).abs() < 0.01);
+      if simulated.info.second_probe.is_some() {
+        let probe2_c = device.temperature_probe2_c.unwrap();
+        assert!((probe2_c - simulated.probe2_c).abs() < 0.01);
+      }
+      if simulated.info.weight {
+        let weight_kg = device.realtime_weight_kg.unwrap();
+        assert!((weight_kg - simulated.weight_kg).abs() < 0.01);
+      }
+      if simulated.info.pressure {
+        let pressure_hpa = device.pressure_hpa.unwrap();
+        assert!((pressure_hpa - simulated.pressure_hpa).abs() < 0.1);
+      }
+      if simulated.info.activity.is_some() {
+        assert_eq!(device.activity, Some(simulated.activity as u8));
+      }
+    }
+  }
+
+  #[test]
+  fn advertisement_for_model_uses_that_model() {
+    let advertisement = advertisement_for_model(57).unwrap();
+    assert_eq!(advertisement.data[0], 57);
+    assert!(advertisement.local_name.starts_with("57:"));
+    assert!(advertisement_for_model(99).is_none());
+  }
+
+  #[test]
+  fn simulated_devices_are_unique() {
+    let first = SimulatedDevice::new(1, 42);
+    let second = SimulatedDevice::new(257, 42);
+    assert_ne!(first.address, second.address);
+    assert_ne!(first.local_name, second.local_name);
+  }
+}
@@ -0,0 +1,188 @@
+use crate::brood_flow_config::Configuration;
+use crate::broodminder_device::{BroodminderDevice, Reading, StateReading};
+use crate::cadence;
+use crate::device_names::UNKNOWN_DEVICE_ID;
+use crate::error::Error;
+use chrono::prelude::Utc;
+use rusqlite::{params, Connection};
+use std::collections::HashMap;
+use std::sync::Arc;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::broadcast::Receiver;
+
+// timestamp is the millisecond epoch time of the reading. Readings a model doesn't have are NULL,
+// and so is humidity until brood-flow can decode the TH's
+const CREATE_READINGS_TABLE: &str = "CREATE TABLE IF NOT EXISTS readings (
+  device_id TEXT NOT NULL,
+  timestamp INTEGER NOT NULL,
+  temp_c REAL NOT NULL,
+  humidity REAL,
+  weight_kg REAL,
+  battery INTEGER NOT NULL,
+  rssi INTEGER
+)";
+
+fn open(path: &str) -> rusqlite::Result<Connection> {
+  let connection = Connection::open(path)?;
+  connection.execute(CREATE_READINGS_TABLE, [])?;
+  Ok(connection)
+}
+
+fn insert(
+  connection: &Connection,
+  device: &BroodminderDevice,
+  reading: &StateReading,
+  now: i64,
+) -> rusqlite::Result<()> {
+  connection.execute(
+    "INSERT INTO readings (device_id, timestamp, temp_c, humidity, weight_kg, battery, rssi)
+     VALUES (?1, ?2, ?3, NULL, ?4, ?5, ?6)",
+    params![
+      device.device_id,
+      now,
+      reading.temperature_c,
+      reading.weight_kg,
+      reading
+        .battery_percent
+        .map(|battery_percent| battery_percent.round() as i64),
+      device.rssi,
+    ],
+  )?;
+  Ok(())
+}
+
+// Writes readings to a local SQLite database. Like the MQTT output this is a subscriber of the
+// readings broadcast with its own copy of each device, so a row is written whenever a state
+// message would be published (following the rate limit and downsample_secs)
+pub fn start(
+  path: &str,
+  mut readings: Receiver<Reading>,
+  settings: Arc<Configuration>,
+) -> Result<(), Error> {
+  let connection = open(path)?;
+  info!("Writing readings to {}", path);
+
+  let mut devices: HashMap<String, BroodminderDevice> = HashMap::new();
+  let cadence_ms = settings.fixed_cadence_secs.map(|secs| secs as i64 * 1000);
+  let mut last_tick = 0;
+
+  tokio::task::spawn(async move {
+    loop {
+      let reading = tokio::select! {
+        reading = readings.recv() => match reading {
+          Ok(reading) => reading,
+          Err(RecvError::Lagged(missed)) => {
+            warn!("SQLite writes fell behind, skipped {} readings", missed);
+            continue;
+          }
+          Err(RecvError::Closed) => break,
+        },
+        // With fixed_cadence_secs a row is written on the timer, like the state messages
+        tick = cadence::next_tick(cadence_ms, last_tick) => {
+          last_tick = tick;
+          for device in devices.values_mut() {
+            if device.device_id != UNKNOWN_DEVICE_ID && !device.is_stale(&settings, tick) {
+              write(&connection, device, &settings, tick);
+            }
+          }
+          continue;
+        }
+      };
+
+      let device = devices
+        .entry(reading.device.address.clone())
+        .and_modify(|device| device.refresh_from(&reading.device))
+        .or_insert_with(|| reading.device.clone());
+      if device.device_id == UNKNOWN_DEVICE_ID {
+        continue;
+      }
+
+      if cadence_ms.is_none() {
+        write(
+          &connection,
+          device,
+          &settings,
+          Utc::now().timestamp_millis(),
+        );
+      }
+    }
+  });
+
+  Ok(())
+}
+
+// Writes the device's reading if one is due
+fn write(
+  connection: &Connection,
+  device: &mut BroodminderDevice,
+  settings: &Configuration,
+  now: i64,
+) {
+  if let Some(state) = device.next_state_reading(settings, now) {
+    // rusqlite blocks, let the runtime move other tasks off this worker meanwhile
+    let result = tokio::task::block_in_place(|| insert(connection, device, &state, now));
+    if let Err(error) = result {
+      error!("Failed to write reading of {}: {}", device.device_id, error);
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn insert_writes_a_row() {
+    let connection = Connection::open_in_memory().unwrap();
+    connection.execute(CREATE_READINGS_TABLE, []).unwrap();
+
+    // A scale, 20.00 kg
+    let mut payload = [0u8; 21];
+    payload[0] = 57;
+    payload[3] = 0x88;
+    payload[9] = 0x13;
+    payload[4] = 80;
+    payload[19] = 0xCF;
+    payload[20] = 0x87;
+    let mut device = BroodminderDevice::build_broodminder_device(&payload).unwrap();
+    device.device_id = "57:00:01".to_string();
+    device.rssi = Some(-70);
+
+    insert(
+      &connection,
+      &device,
+      &device.state_reading(2),
+      1_700_000_000_000,
+    )
+    .unwrap();
+
+    let (device_id, timestamp, temp_c, humidity, weight_kg, battery, rssi): (
+      String,
+      i64,
+      f64,
+      Option<f64>,
+      Option<f64>,
+      i64,
+      Option<i64>,
+    ) = connection
+      .query_row("SELECT * FROM readings", [], |row| {
+        Ok((
+          row.get(0)?,
+          row.get(1)?,
+          row.get(2)?,
+          row.get(3)?,
+          row.get(4)?,
+          row.get(5)?,
+          row.get(6)?,
+        ))
+      })
+      .unwrap();
+    assert_eq!(device_id, "57:00:01");
+    assert_eq!(timestamp, 1_700_000_000_000);
+    assert_eq!(temp_c, 0.0);
+    assert_eq!(humidity, None);
+    assert!((weight_kg.unwrap() - 20.0).abs() < 0.01);
+    assert_eq!(battery, 80);
+    assert_eq!(rssi, Some(-70));
+  }
+}
@@ -0,0 +1,107 @@
+use crate::brood_flow_config::Configuration;
+use crate::broodminder_device::{self, BroodminderDevice};
+use crate::device_names::DeviceNames;
+use crate::error::Error;
+use crate::gateway;
+use crate::mqtt_options;
+use crate::publisher::Publisher;
+use crate::simulator;
+use chrono::prelude::Utc;
+use config::ConfigError;
+use rumqttc::{AsyncClient, Event, EventLoop, Incoming, Outgoing};
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+
+// How long to wait for the broker to accept the connection
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(30);
+// Once the eventloop has been quiet this long the messages are taken to be delivered
+const SETTLE_TIME: Duration = Duration::from_secs(2);
+
+// Publishes one synthetic device of `model` through the normal config and state messages, for
+// checking the Home Assistant side before any real sensor is in range. The entities are left in
+// HA, delete the device there once done
+pub async fn run(model: u8, settings: &Configuration) -> Result<(), Error> {
+  let advertisement = simulator::advertisement_for_model(model).ok_or_else(|| {
+    ConfigError::Message(format!(
+      "--test-publish: unknown model {}, known models are {:?}",
+      model,
+      broodminder_device::known_models()
+    ))
+  })?;
+
+  // The first reading goes out straight away, whatever the usual publishing cadence
+  let mut settings = settings.clone();
+  settings.publish_on_first_seen = true;
+  settings.downsample_secs = None;
+  settings.fixed_cadence_secs = None;
+  // A running brood-flow with the same client id would be kicked off the broker
+  settings.client_id = format!("{}-test", settings.client_id);
+
+  let (client, mut eventloop) = AsyncClient::new(mqtt_options::build_mqtt_options(&settings)?, 10);
+  let publisher = Publisher::new(
+    client.clone(),
+    settings.max_publishes_per_sec,
+    settings.rate_limit_overflow,
+    settings.max_concurrent_publishes,
+  );
+  tokio::time::timeout(CONNECT_TIMEOUT, wait_for_connack(&mut eventloop))
+    .await
+    .map_err(|_| Error::Mqtt("Timed out connecting to the broker".to_string()))??;
+  info!(
+    "Connected to the broker, publishing a test model {} device",
+    model
+  );
+  // The entities use the availability topic, they'd show as unavailable without it
+  gateway::send_online_message(
+    &publisher,
+    &settings.availability_topic,
+    settings.availability_qos.qos(),
+    settings.availability_retain,
+  );
+
+  let info = settings.model_info(advertisement.data[0]);
+  let mut device = BroodminderDevice::build_with_model_info(&advertisement.data, info)?;
+  device.device_id = DeviceNames::with_fixed_ids(&settings.mac_to_id)
+    .resolve(&advertisement.local_name, &advertisement.address);
+  device.local_name = advertisement.local_name;
+  device.address = advertisement.address;
+  device.manufacturer_lengths = advertisement.manufacturer_lengths;
+  device.check_weight_range(&settings);
+  let now = Utc::now().timestamp_millis();
+  device.record_source(advertisement.adapter, advertisement.rssi, now);
+  info!("Test device: {}", device);
+
+  if settings.publish_discovery {
+    device.send_config_messages(&publisher, &settings, now);
+  } else {
+    warn!("publish_discovery is false, only sending the state message");
+  }
+  device.send_state_message(&publisher, &settings, now);
+
+  // Publishes run on their own tasks, keep the eventloop going until they've all gone out
+  while let Ok(event) = tokio::time::timeout(SETTLE_TIME, eventloop.poll()).await {
+    event?;
+  }
+  info!(
+    "Sent {} messages for {} to {}",
+    publisher.sent_counter().load(Ordering::Relaxed),
+    device.device_id,
+    device.state_topic(&settings)
+  );
+  // Disconnecting cleanly keeps the broker from publishing our "offline" last will
+  client.disconnect().await?;
+  while let Ok(Ok(event)) = tokio::time::timeout(SETTLE_TIME, eventloop.poll()).await {
+    if let Event::Outgoing(Outgoing::Disconnect) = event {
+      break;
+    }
+  }
+  Ok(())
+}
+
+async fn wait_for_connack(eventloop: &mut EventLoop) -> Result<(), Error> {
+  loop {
+    if let Event::Incoming(Incoming::ConnAck(_)) = eventloop.poll().await? {
+      return Ok(());
+    }
+  }
+}
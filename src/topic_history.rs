@@ -0,0 +1,120 @@
+use crate::broodminder_device::DiscoveryEntry;
+use json::{object, JsonValue};
+use std::fs;
+
+// Remembers where each device's entities were published (by MAC address) in state_file, so when a
+// device's topics change between runs (e.g. it was renamed, or a topic template changed) the old
+// entities can be deleted from Home Assistant rather than left behind, unavailable
+pub struct TopicHistory {
+  path: String,
+  devices: JsonValue, // {"<address>": {"state_topic": ..., "entities": [{sensor_id, config_topic, unique_id}]}}
+}
+
+impl TopicHistory {
+  // A missing or unreadable file starts an empty history
+  pub fn load(path: &str) -> TopicHistory {
+    let devices = match fs::read_to_string(path) {
+      Ok(contents) => json::parse(&contents).unwrap_or_else(|error| {
+        warn!("Ignoring unreadable state_file {}: {}", path, error);
+        JsonValue::new_object()
+      }),
+      Err(_) => JsonValue::new_object(),
+    };
+    TopicHistory {
+      path: path.to_string(),
+      devices,
+    }
+  }
+
+  // Records where a device is published now. If its state topic has changed since it was last
+  // recorded, returns the entities it was published under before
+  pub fn record(
+    &mut self,
+    address: &str,
+    state_topic: &str,
+    entries: &[DiscoveryEntry],
+  ) -> Option<Vec<DiscoveryEntry>> {
+    let previous = &self.devices[address];
+    let current = entries_to_json(entries);
+    if previous["state_topic"] == state_topic && previous["entities"] == current {
+      return None;
+    }
+
+    let moved = if previous.is_null() || previous["state_topic"] == state_topic {
+      None
+    } else {
+      Some(
+        previous["entities"]
+          .members()
+          .map(|entity| DiscoveryEntry {
+            sensor_id: entity["sensor_id"].to_string(),
+            config_topic: entity["config_topic"].to_string(),
+            unique_id: entity["unique_id"].to_string(),
+          })
+          .collect(),
+      )
+    };
+
+    self.devices[address] = object! {
+      state_topic: state_topic,
+      entities: current,
+    };
+    if let Err(error) = fs::write(&self.path, self.devices.pretty(2)) {
+      warn!("Couldn't write state_file {}: {}", self.path, error);
+    }
+    moved
+  }
+}
+
+fn entries_to_json(entries: &[DiscoveryEntry]) -> JsonValue {
+  let mut entities = JsonValue::new_array();
+  for entry in entries {
+    let _ = entities.push(object! {
+      sensor_id: entry.sensor_id.clone(),
+      config_topic: entry.config_topic.clone(),
+      unique_id: entry.unique_id.clone(),
+    });
+  }
+  entities
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn entry(device: &str) -> DiscoveryEntry {
+    DiscoveryEntry {
+      sensor_id: "temperature".to_string(),
+      config_topic: format!("homeassistant/sensor/BM{}Temp/config", device),
+      unique_id: format!("{}_temperature", device),
+    }
+  }
+
+  #[test]
+  fn changed_topics_return_the_old_entities() {
+    let path = std::env::temp_dir().join(format!("brood-flow-topics-{}.json", std::process::id()));
+    let path = path.to_str().unwrap();
+    let _ = fs::remove_file(path);
+
+    let mut history = TopicHistory::load(path);
+    assert_eq!(
+      history.record("5E:00:00:00:00:01", "a/state", &[entry("470101")]),
+      None
+    );
+    assert_eq!(
+      history.record("5E:00:00:00:00:01", "a/state", &[entry("470101")]),
+      None
+    );
+
+    // Survives a restart
+    let mut history = TopicHistory::load(path);
+    let moved = history.record("5E:00:00:00:00:01", "b/state", &[entry("hive1")]);
+    assert_eq!(moved, Some(vec![entry("470101")]));
+    assert_eq!(
+      history.record("5E:00:00:00:00:01", "b/state", &[entry("hive1")]),
+      None
+    );
+
+    fs::remove_file(path).unwrap();
+  }
+}
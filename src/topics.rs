@@ -0,0 +1,176 @@
+// Topic templates let users restructure every topic brood-flow publishes to. Placeholders are
+// written as {name}, e.g. "{prefix}/{component}/BM{device_id}{sensor}/config"
+
+pub const DEFAULT_STATE_TOPIC_TEMPLATE: &str = "{prefix}/{component}/BM{device_id}/state";
+pub const DEFAULT_ATTRIBUTES_TOPIC_TEMPLATE: &str = "{prefix}/{component}/BM{device_id}/attributes";
+pub const DEFAULT_CONFIG_TOPIC_TEMPLATE: &str = "{prefix}/{component}/BM{device_id}{sensor}/config";
+
+// Placeholders available to state/attributes templates. Config templates also get {sensor}, since
+// each sensor of a device needs its own config topic
+pub const DEVICE_PLACEHOLDERS: [&str; 3] = ["prefix", "component", "device_id"];
+pub const SENSOR_PLACEHOLDERS: [&str; 4] = ["prefix", "component", "device_id", "sensor"];
+
+// The values substituted into a template
+pub struct TopicValues<'a> {
+  pub prefix: &'a str,    // The discovery prefix, "homeassistant" by default
+  pub component: &'a str, // The HA component, e.g. "sensor"
+  pub device_id: &'a str, // The device id in its canonical form, e.g. "470101"
+  pub sensor: &'a str,    // The sensor suffix, e.g. "Temp" (config topics only)
+}
+
+// The form of a device id (or MAC address) used in topics and entity ids. Firmware names sensors
+// "47:01:01", "470101" or "BM-47-01-01", which all become "470101": separators are dropped, and so
+// is a separated "BM" prefix, since the topics add their own. Underscores are kept for the suffix
+// DeviceNames gives sensors sharing a name ("47:01:01_2")
+pub fn canonical_id(id: &str) -> String {
+  let id = match id.strip_prefix("BM") {
+    Some(rest) if rest.starts_with(|c: char| !c.is_ascii_alphanumeric()) => rest,
+    _ => id,
+  };
+  id.chars()
+    .filter(|c| c.is_ascii_alphanumeric() || *c == '_')
+    .collect()
+}
+
+pub fn render(template: &str, values: &TopicValues) -> String {
+  template
+    .replace("{prefix}", values.prefix)
+    .replace("{component}", values.component)
+    .replace("{device_id}", values.device_id)
+    .replace("{sensor}", values.sensor)
+}
+
+// Checks every {placeholder} in the template is one of the allowed ones
+pub fn validate(name: &str, template: &str, allowed: &[&str]) -> Result<(), String> {
+  let mut rest = template;
+  while let Some(start) = rest.find('{') {
+    let end = rest[start..]
+      .find('}')
+      .ok_or_else(|| format!("{} has an unclosed placeholder: {}", name, template))?;
+    let placeholder = &rest[start + 1..start + end];
+    if !allowed.contains(&placeholder) {
+      return Err(format!(
+        "{} uses unknown placeholder {{{}}}, expected one of: {}",
+        name,
+        placeholder,
+        allowed.join(", ")
+      ));
+    }
+    rest = &rest[start + end + 1..];
+  }
+
+  Ok(())
+}
+
+// A topic_namespace is the first levels of every topic, so it can't have wildcards or empty levels.
+// Topics starting with $ are reserved by brokers (e.g. $SYS), which Home Assistant can't discover
+pub fn validate_namespace(namespace: &str) -> Result<(), String> {
+  if namespace.split('/').any(str::is_empty) {
+    return Err(format!(
+      "topic_namespace can't be empty or have empty levels: {:?}",
+      namespace
+    ));
+  }
+  if namespace.contains(['+', '#']) {
+    return Err(format!(
+      "topic_namespace can't contain the wildcards + or #: {}",
+      namespace
+    ));
+  }
+  if namespace.starts_with('$') {
+    return Err(format!(
+      "topic_namespace can't start with $, brokers reserve those topics: {}",
+      namespace
+    ));
+  }
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn default_templates_render_current_topics() {
+    let values = TopicValues {
+      prefix: "homeassistant",
+      component: "sensor",
+      device_id: "470101",
+      sensor: "Temp",
+    };
+    assert_eq!(
+      render(DEFAULT_STATE_TOPIC_TEMPLATE, &values),
+      "homeassistant/sensor/BM470101/state"
+    );
+    assert_eq!(
+      render(DEFAULT_CONFIG_TOPIC_TEMPLATE, &values),
+      "homeassistant/sensor/BM470101Temp/config"
+    );
+  }
+
+  #[test]
+  fn validate_rejects_unknown_placeholders() {
+    assert!(validate(
+      "state_topic_template",
+      DEFAULT_STATE_TOPIC_TEMPLATE,
+      &DEVICE_PLACEHOLDERS
+    )
+    .is_ok());
+    assert!(validate(
+      "config_topic_template",
+      DEFAULT_CONFIG_TOPIC_TEMPLATE,
+      &SENSOR_PLACEHOLDERS
+    )
+    .is_ok());
+    assert!(validate(
+      "state_topic_template",
+      "hives/{device_id}",
+      &DEVICE_PLACEHOLDERS
+    )
+    .is_ok());
+
+    let error = validate(
+      "state_topic_template",
+      "hives/{device}/{sensor}",
+      &DEVICE_PLACEHOLDERS,
+    )
+    .unwrap_err();
+    assert!(error.contains("{device}"));
+    assert!(validate(
+      "state_topic_template",
+      "hives/{sensor}",
+      &DEVICE_PLACEHOLDERS
+    )
+    .is_err());
+    assert!(validate(
+      "state_topic_template",
+      "hives/{device_id",
+      &DEVICE_PLACEHOLDERS
+    )
+    .is_err());
+  }
+
+  #[test]
+  fn validate_namespace_rejects_unusable_prefixes() {
+    assert!(validate_namespace("tenant-a").is_ok());
+    assert!(validate_namespace("tenants/a").is_ok());
+    assert!(validate_namespace("").is_err());
+    assert!(validate_namespace("tenant-a/").is_err());
+    assert!(validate_namespace("/tenant-a").is_err());
+    assert!(validate_namespace("tenants/+").is_err());
+    assert!(validate_namespace("#").is_err());
+    assert!(validate_namespace("$SYS").is_err());
+  }
+
+  #[test]
+  fn canonical_ids_drop_separators() {
+    assert_eq!(canonical_id("47:01:01"), "470101");
+    assert_eq!(canonical_id("470101"), "470101");
+    assert_eq!(canonical_id("BM-47-01-01"), "470101");
+    assert_eq!(canonical_id("47.01.01"), "470101");
+    assert_eq!(canonical_id("47:01:01_2"), "470101_2");
+    assert_eq!(canonical_id("5E:00:00:00:00:01"), "5E0000000001");
+    // Only a separated prefix is Broodminder's, "BMX" is a name like any other
+    assert_eq!(canonical_id("BMX-1"), "BMX1");
+  }
+}
@@ -0,0 +1,47 @@
+use chrono::prelude::Utc;
+use json::object;
+use std::time::Duration;
+use tokio::task::JoinHandle;
+
+// Out-of-band notifications (e.g. to ntfy or a chat webhook) for when the broker, the usual alert
+// path, may be the thing that's down. Without a webhook_url every notification is a no-op
+#[derive(Clone, Default)]
+pub struct Webhook {
+  url: Option<String>,
+}
+
+impl Webhook {
+  pub fn new(url: Option<String>) -> Webhook {
+    Webhook { url }
+  }
+
+  // POSTs {"event": ..., "detail": ..., "timestamp": ...} to the webhook without waiting for it.
+  // Await the handle before exiting, or the request may never be sent
+  pub fn notify(&self, event: &'static str, detail: String) -> Option<JoinHandle<()>> {
+    let url = self.url.clone()?;
+    let body = object! {
+      event: event,
+      detail: detail,
+      timestamp: Utc::now().to_rfc3339(),
+    }
+    .dump();
+
+    // ureq blocks, so keep it off the runtime's workers
+    Some(tokio::task::spawn_blocking(move || {
+      let result = ureq::post(&url)
+        .timeout(Duration::from_secs(10))
+        .set("Content-Type", "application/json")
+        .send_string(&body);
+      if let Err(error) = result {
+        warn!("Couldn't notify webhook of {}: {}", event, error);
+      }
+    }))
+  }
+
+  // Notifies and waits for the request to finish, for when brood-flow is about to exit
+  pub async fn notify_and_wait(&self, event: &'static str, detail: String) {
+    if let Some(handle) = self.notify(event, detail) {
+      let _ = handle.await;
+    }
+  }
+}